@@ -0,0 +1,146 @@
+use super::config::access_log;
+use axum::http::{Method, StatusCode};
+
+pub const FIELDS: [&str; 10] = [
+    "provider",
+    "location",
+    "query",
+    "format",
+    "input_bytes",
+    "output_bytes",
+    "input_dimensions",
+    "output_dimensions",
+    "fallback",
+    "cache",
+];
+
+// What the handler learns about a request, carried to the logger as a response extension.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Record {
+    pub provider: Option<String>,
+    pub location: Option<String>,
+    pub query: Option<String>,
+    pub input_bytes: Option<usize>,
+    pub input_dimensions: Option<(u32, u32)>,
+    pub output_dimensions: Option<(u32, u32)>,
+    pub fallback: Option<&'static str>,
+    pub cache: Option<&'static str>,
+}
+
+// What is known only once a response is made.
+#[derive(Debug)]
+pub struct Outcome<'a> {
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub status: StatusCode,
+    pub latency: std::time::Duration,
+    pub format: Option<&'a str>,
+    pub output_bytes: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct Logger {
+    fields: Vec<&'static str>,
+    sample_rate: f64,
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Logger {
+    pub fn new(cfg: Option<access_log::Config>) -> Self {
+        let Some(cfg) = cfg else {
+            return Self {
+                fields: FIELDS.to_vec(),
+                sample_rate: 1.0,
+            };
+        };
+        let fields = match cfg.fields {
+            Some(names) => names
+                .iter()
+                .map(|name| {
+                    FIELDS
+                        .into_iter()
+                        .find(|v| v == name)
+                        .unwrap_or_else(|| panic!("unknown access log field: {name}"))
+                })
+                .collect(),
+            None => FIELDS.to_vec(),
+        };
+        let sample_rate = cfg.sample_rate.unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&sample_rate) {
+            panic!("access log sample_rate must be between 0 and 1");
+        }
+        Self {
+            fields,
+            sample_rate,
+        }
+    }
+
+    // Server errors are always logged so that sampling never hides them.
+    pub fn sampled(&self, status: StatusCode) -> bool {
+        status.is_server_error()
+            || self.sample_rate >= 1.0
+            || rand::random::<f64>() < self.sample_rate
+    }
+
+    fn pick<T>(&self, name: &str, value: Option<T>) -> Option<T> {
+        value.filter(|_| self.fields.contains(&name))
+    }
+
+    // https://docs.rs/tracing/latest/tracing/index.html#recording-fields
+    pub fn log(&self, outcome: &Outcome, record: &Record) {
+        let dimensions = |v: Option<(u32, u32)>| v.map(|(w, h)| format!("{w}x{h}"));
+        tracing::info!(
+            method = outcome.method.as_str(),
+            path = outcome.path,
+            status = outcome.status.as_u16(),
+            latency_ms = outcome.latency.as_millis() as u64,
+            provider = self.pick("provider", record.provider.as_deref()),
+            location = self.pick("location", record.location.as_deref()),
+            query = self.pick("query", record.query.as_deref()),
+            format = self.pick("format", outcome.format),
+            input_bytes = self.pick("input_bytes", record.input_bytes.map(|v| v as u64)),
+            output_bytes = self.pick("output_bytes", outcome.output_bytes),
+            input_dimensions = self.pick("input_dimensions", dimensions(record.input_dimensions)),
+            output_dimensions =
+                self.pick("output_dimensions", dimensions(record.output_dimensions)),
+            fallback = self.pick("fallback", record.fallback),
+            cache = self.pick("cache", record.cache),
+            "access"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logger() {
+        let logger = Logger::new(None);
+        assert_eq!(logger.pick("cache", Some("hit")), Some("hit"));
+        assert!(logger.sampled(StatusCode::OK));
+
+        let logger = Logger::new(Some(access_log::Config {
+            fields: Some(Vec::from(["location".to_string(), "cache".to_string()])),
+            sample_rate: Some(0.0),
+        }));
+        assert_eq!(logger.pick("location", Some("s3://b/k")), Some("s3://b/k"));
+        assert_eq!(logger.pick("query", Some("w=300")), None);
+        assert!(!logger.sampled(StatusCode::OK));
+        assert!(!logger.sampled(StatusCode::NOT_FOUND));
+        assert!(logger.sampled(StatusCode::SERVICE_UNAVAILABLE));
+
+        let unknown = std::panic::catch_unwind(|| {
+            Logger::new(Some(access_log::Config {
+                fields: Some(Vec::from(["referer".to_string()])),
+                sample_rate: None,
+            }))
+        });
+        assert!(unknown.is_err());
+    }
+}
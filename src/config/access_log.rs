@@ -0,0 +1,7 @@
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub fields: Option<Vec<String>>,
+    pub sample_rate: Option<f64>,
+}
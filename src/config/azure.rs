@@ -0,0 +1,9 @@
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Config {
+    pub account_name: String,
+    pub account_key: Option<String>,
+    pub sas_token: Option<String>,
+    pub endpoint_url: Option<String>,
+}
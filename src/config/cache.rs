@@ -0,0 +1,8 @@
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub ttl_seconds: u64,
+    pub max_entries: usize,
+    pub max_bytes: Option<usize>,
+}
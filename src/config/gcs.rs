@@ -0,0 +1,8 @@
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Config {
+    pub endpoint_url: Option<String>,
+    pub credentials_path: Option<String>,
+    pub anonymous: Option<bool>,
+}
@@ -0,0 +1,7 @@
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub probe_ttl_seconds: u64,
+    pub probe_timeout_ms: u64,
+}
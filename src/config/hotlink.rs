@@ -0,0 +1,8 @@
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub allowed_referers: Vec<String>,
+    pub allow_empty_referer: Option<bool>,
+    pub use_fallback: Option<bool>,
+}
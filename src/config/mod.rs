@@ -1,23 +1,82 @@
+pub mod access_log;
+pub mod azure;
+pub mod cache;
+pub mod circuit_breaker;
+pub mod cors;
+pub mod gcs;
+pub mod health;
+pub mod hotlink;
+pub mod processing;
+pub mod rate_limit;
+pub mod retry;
+pub mod rewrite;
 pub mod s3;
+pub mod telemetry;
+pub mod text;
+pub mod timeout;
+pub mod watermark;
 pub mod web;
 
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufReader, Read};
 use std::path::Path;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct Provider {
     pub path: String,
-    pub src: String,
+    pub hosts: Option<Vec<String>>,
+    pub src: Src,
     pub fallback_path: Option<String>,
     pub success_even_no_content: Option<bool>,
+    pub circuit_breaker: Option<circuit_breaker::Config>,
+    pub s3_client: Option<String>,
+    pub replicate_to: Option<String>,
+    pub rewrites: Option<Vec<rewrite::Rule>>,
+    pub rate_limit: Option<rate_limit::Config>,
+    pub hotlink: Option<hotlink::Config>,
+    pub cors: Option<cors::Config>,
+    pub watermark: Option<watermark::Config>,
+}
+
+// A provider takes either a single source or an ordered list of sources to try in turn.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Src {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Src {
+    pub fn to_vec(&self) -> Vec<String> {
+        match self {
+            Self::One(v) => Vec::from([v.clone()]),
+            Self::Many(v) => v.clone(),
+        }
+    }
+}
+
+impl Default for Src {
+    fn default() -> Self {
+        Self::Many(Vec::new())
+    }
+}
+
+impl From<String> for Src {
+    fn from(v: String) -> Self {
+        Self::One(v)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Client {
     pub s3: s3::Config,
+    pub s3_clients: Option<HashMap<String, s3::Config>>,
     pub web: web::Config,
+    pub gcs: Option<gcs::Config>,
+    pub azure: Option<azure::Config>,
+    pub retry: Option<retry::Config>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -29,6 +88,16 @@ pub struct Config {
     pub profile_path: Option<String>,
     pub use_embedded_profile: Option<bool>,
     pub suppress_logging: Option<bool>,
+    pub cache: Option<cache::Config>,
+    pub processing: Option<processing::Config>,
+    pub timeout: Option<timeout::Config>,
+    pub rate_limit: Option<rate_limit::Config>,
+    pub trusted_proxies: Option<Vec<String>>,
+    pub cors: Option<cors::Config>,
+    pub telemetry: Option<telemetry::Config>,
+    pub health: Option<health::Config>,
+    pub access_log: Option<access_log::Config>,
+    pub text: Option<text::Config>,
     pub client: Client,
     pub providers: Vec<Provider>,
 }
@@ -71,6 +140,50 @@ mod tests {
               "fallback_path": "/foo/no_img.jpg",
               "profile_path": "/bar/default.icc",
               "use_embedded_profile": true,
+              "cache": {
+                "ttl_seconds": 60,
+                "max_entries": 1000,
+                "max_bytes": 268435456
+              },
+              "processing": {
+                "threads": 4,
+                "queue_depth": 256,
+                "queue_timeout_ms": 3000
+              },
+              "timeout": {
+                "request_ms": 10000,
+                "fetch_ms": 4000
+              },
+              "rate_limit": {
+                "per_second": 50,
+                "burst": 100,
+                "costs": {
+                  "avif": 5,
+                  "blur": 3,
+                  "text": 2
+                }
+              },
+              "trusted_proxies": ["10.0.0.0/8", "192.0.2.10"],
+              "cors": {
+                "allowed_origins": ["*"],
+                "max_age_seconds": 600
+              },
+              "telemetry": {
+                "otlp_endpoint": "http://127.0.0.1:4318/v1/traces",
+                "sample_ratio": 0.1
+              },
+              "health": {
+                "probe_ttl_seconds": 10,
+                "probe_timeout_ms": 2000
+              },
+              "access_log": {
+                "fields": ["provider", "location", "query", "cache"],
+                "sample_rate": 0.5
+              },
+              "text": {
+                "font_dir": "fonts",
+                "default_font": "DejaVuSans"
+              },
               "client": {
                 "s3": {
                   "aws_region": "ap-northeast-1",
@@ -81,16 +194,92 @@ mod tests {
                 "web": {
                   "user_agent": "fanlin-rs/0.0.1",
                   "timeout": 5
+                },
+                "s3_clients": {
+                  "archive": {
+                    "aws_region": "us-west-2",
+                    "aws_profile": "archive",
+                    "aws_role_arn": "arn:aws:iam::123456789012:role/fanlin",
+                    "aws_role_session_name": "fanlin-archive",
+                    "aws_external_id": "dummy_id"
+                  }
+                },
+                "gcs": {
+                  "endpoint_url": "http://127.0.0.1:4443",
+                  "anonymous": true
+                },
+                "azure": {
+                  "account_name": "devstoreaccount1",
+                  "account_key": "ZHVtbXk=",
+                  "endpoint_url": "http://127.0.0.1:10000/devstoreaccount1"
+                },
+                "retry": {
+                  "max_attempts": 3,
+                  "base_delay_ms": 50,
+                  "max_delay_ms": 1000
                 }
               },
               "providers": [
                 {
                   "path": "foo",
-                  "src": "s3://local-test/images"
+                  "src": "s3://local-test/images",
+                  "circuit_breaker": {
+                    "failure_threshold": 5,
+                    "open_seconds": 30
+                  }
+                },
+                {
+                  "path": "qux",
+                  "hosts": ["img.example.com", "*.example.net"],
+                  "src": "s3://archive/images",
+                  "s3_client": "archive",
+                  "rate_limit": {
+                    "per_second": 5,
+                    "burst": 10
+                  },
+                  "hotlink": {
+                    "allowed_referers": ["example.com", "*.example.com"],
+                    "allow_empty_referer": true
+                  },
+                  "cors": {
+                    "allowed_origins": ["https://editor.example.com"],
+                    "allowed_methods": ["GET", "HEAD"],
+                    "allowed_headers": ["Content-Type"],
+                    "exposed_headers": ["Server-Timing"],
+                    "allow_credentials": true
+                  },
+                  "watermark": {
+                    "path": "/qux/logo.png",
+                    "position": "bottom-right",
+                    "margin": 16,
+                    "opacity": 60,
+                    "scale": 20,
+                    "enforced": true
+                  }
                 },
                 {
                   "path": "bar",
                   "src": "http://127.0.0.1:3000/foo"
+                },
+                {
+                  "path": "quux",
+                  "src": [
+                    "s3://new-bucket/images",
+                    "http://legacy-origin/images",
+                    "file://localhost/var/lib/images"
+                  ],
+                  "replicate_to": "s3://new-bucket/images",
+                  "rewrites": [
+                    {
+                      "pattern": "^(?P<id>(?P<shard>[0-9a-z]{2})[0-9a-z]*)/thumb\\.jpg$",
+                      "replacement": "originals/$shard/$id.jpg",
+                      "query": "w=150&h=150"
+                    },
+                    {
+                      "pattern": "^(?P<id>(?P<shard>[0-9a-z]{2})[0-9a-z]*)/(?P<size>\\w+)\\.jpg$",
+                      "replacement": "originals/$shard/$id.jpg"
+                    }
+                  ]
                 }
               ]
             }
@@ -103,6 +292,62 @@ mod tests {
         assert_eq!(got.fallback_path, Some("/foo/no_img.jpg".to_string()));
         assert_eq!(got.profile_path, Some("/bar/default.icc".to_string()));
         assert_eq!(got.use_embedded_profile, Some(true));
+        let cache = got.cache.expect("cache is missing");
+        assert_eq!(cache.ttl_seconds, 60);
+        assert_eq!(cache.max_entries, 1000);
+        assert_eq!(cache.max_bytes, Some(268435456));
+        let processing = got.processing.expect("processing is missing");
+        assert_eq!(processing.threads, 4);
+        assert_eq!(processing.queue_depth.get(), 256);
+        assert_eq!(processing.queue_timeout_ms, 3000);
+        let timeout = got.timeout.expect("timeout is missing");
+        assert_eq!(timeout.request_ms, 10000);
+        assert_eq!(timeout.fetch_ms, Some(4000));
+        assert_eq!(timeout.process_ms, None);
+        let rate_limit = got.rate_limit.expect("rate_limit is missing");
+        assert_eq!(rate_limit.per_second, 50.0);
+        assert_eq!(rate_limit.burst, 100.0);
+        let costs = rate_limit.costs.expect("costs is missing");
+        assert_eq!(costs.avif, Some(5.0));
+        assert_eq!(costs.webp, None);
+        assert_eq!(costs.blur, Some(3.0));
+        assert_eq!(costs.text, Some(2.0));
+        assert_eq!(
+            got.trusted_proxies,
+            Some(Vec::from([
+                "10.0.0.0/8".to_string(),
+                "192.0.2.10".to_string(),
+            ]))
+        );
+        let cors = got.cors.expect("cors is missing");
+        assert_eq!(cors.allowed_origins, Vec::from(["*".to_string()]));
+        assert_eq!(cors.allowed_methods, None);
+        assert_eq!(cors.max_age_seconds, Some(600));
+        assert_eq!(cors.allow_credentials, None);
+        let telemetry = got.telemetry.expect("telemetry is missing");
+        assert_eq!(
+            telemetry.otlp_endpoint,
+            "http://127.0.0.1:4318/v1/traces".to_string()
+        );
+        assert_eq!(telemetry.service_name, None);
+        assert_eq!(telemetry.sample_ratio, Some(0.1));
+        let health = got.health.expect("health is missing");
+        assert_eq!(health.probe_ttl_seconds, 10);
+        assert_eq!(health.probe_timeout_ms, 2000);
+        let access_log = got.access_log.expect("access_log is missing");
+        assert_eq!(
+            access_log.fields,
+            Some(vec![
+                "provider".to_string(),
+                "location".to_string(),
+                "query".to_string(),
+                "cache".to_string(),
+            ])
+        );
+        assert_eq!(access_log.sample_rate, Some(0.5));
+        let text = got.text.expect("text is missing");
+        assert_eq!(text.font_dir, "fonts".to_string());
+        assert_eq!(text.default_font, Some("DejaVuSans".to_string()));
         assert_eq!(got.client.s3.aws_region, "ap-northeast-1".to_string());
         assert_eq!(
             got.client.s3.aws_endpoint_url,
@@ -116,14 +361,149 @@ mod tests {
             got.client.s3.aws_secret_access_key,
             Some("dummy_secret".to_string())
         );
-        assert_eq!(got.providers.len(), 2);
+        let gcs = got.client.gcs.expect("gcs is missing");
+        assert_eq!(gcs.endpoint_url, Some("http://127.0.0.1:4443".to_string()));
+        assert_eq!(gcs.credentials_path, None);
+        assert_eq!(gcs.anonymous, Some(true));
+        let azure = got.client.azure.expect("azure is missing");
+        assert_eq!(azure.account_name, "devstoreaccount1".to_string());
+        assert_eq!(azure.account_key, Some("ZHVtbXk=".to_string()));
+        assert_eq!(azure.sas_token, None);
+        assert_eq!(
+            azure.endpoint_url,
+            Some("http://127.0.0.1:10000/devstoreaccount1".to_string())
+        );
+        let retry = got.client.retry.expect("retry is missing");
+        assert_eq!(retry.max_attempts, 3);
+        assert_eq!(retry.base_delay_ms, 50);
+        assert_eq!(retry.max_delay_ms, 1000);
+        let s3_clients = got.client.s3_clients.expect("s3_clients is missing");
+        assert_eq!(s3_clients["archive"].aws_region, "us-west-2".to_string());
+        assert_eq!(s3_clients["archive"].aws_endpoint_url, None);
+        assert_eq!(
+            s3_clients["archive"].aws_profile,
+            Some("archive".to_string())
+        );
+        assert_eq!(
+            s3_clients["archive"].aws_role_arn,
+            Some("arn:aws:iam::123456789012:role/fanlin".to_string())
+        );
+        assert_eq!(
+            s3_clients["archive"].aws_role_session_name,
+            Some("fanlin-archive".to_string())
+        );
+        assert_eq!(
+            s3_clients["archive"].aws_external_id,
+            Some("dummy_id".to_string())
+        );
+        assert_eq!(s3_clients["archive"].aws_web_identity_token_file, None);
+        assert_eq!(got.providers.len(), 4);
         assert_eq!(got.providers[0].path, "foo".to_string());
-        assert_eq!(got.providers[0].src, "s3://local-test/images".to_string());
-        assert_eq!(got.providers[1].path, "bar".to_string());
         assert_eq!(
-            got.providers[1].src,
-            "http://127.0.0.1:3000/foo".to_string()
+            got.providers[0].src,
+            Src::One("s3://local-test/images".to_string())
         );
+        assert_eq!(got.providers[0].s3_client, None);
+        assert_eq!(got.providers[1].s3_client, Some("archive".to_string()));
+        assert_eq!(got.providers[0].hosts, None);
+        assert_eq!(
+            got.providers[1].hosts,
+            Some(Vec::from([
+                "img.example.com".to_string(),
+                "*.example.net".to_string(),
+            ]))
+        );
+        assert_eq!(got.providers[2].path, "bar".to_string());
+        assert_eq!(
+            got.providers[2].src,
+            Src::One("http://127.0.0.1:3000/foo".to_string())
+        );
+        assert_eq!(
+            got.providers[3].src.to_vec(),
+            Vec::from([
+                "s3://new-bucket/images".to_string(),
+                "http://legacy-origin/images".to_string(),
+                "file://localhost/var/lib/images".to_string(),
+            ])
+        );
+        assert_eq!(got.providers[0].replicate_to, None);
+        assert_eq!(
+            got.providers[3].replicate_to,
+            Some("s3://new-bucket/images".to_string())
+        );
+        assert!(got.providers[0].rewrites.is_none());
+        let rewrites = got.providers[3]
+            .rewrites
+            .clone()
+            .expect("rewrites is missing");
+        assert_eq!(rewrites.len(), 2);
+        assert_eq!(
+            rewrites[0].pattern,
+            r"^(?P<id>(?P<shard>[0-9a-z]{2})[0-9a-z]*)/thumb\.jpg$".to_string()
+        );
+        assert_eq!(
+            rewrites[0].replacement,
+            "originals/$shard/$id.jpg".to_string()
+        );
+        assert_eq!(rewrites[0].query, Some("w=150&h=150".to_string()));
+        assert_eq!(rewrites[1].query, None);
+        let breaker = got.providers[0]
+            .circuit_breaker
+            .clone()
+            .expect("circuit_breaker is missing");
+        assert_eq!(breaker.failure_threshold, 5);
+        assert_eq!(breaker.open_seconds, 30);
+        assert!(got.providers[2].circuit_breaker.is_none());
+        assert!(got.providers[0].rate_limit.is_none());
+        let rate_limit = got.providers[1]
+            .rate_limit
+            .clone()
+            .expect("rate_limit is missing");
+        assert_eq!(rate_limit.per_second, 5.0);
+        assert_eq!(rate_limit.burst, 10.0);
+        assert!(rate_limit.costs.is_none());
+        assert!(got.providers[0].hotlink.is_none());
+        let hotlink = got.providers[1]
+            .hotlink
+            .clone()
+            .expect("hotlink is missing");
+        assert_eq!(
+            hotlink.allowed_referers,
+            Vec::from(["example.com".to_string(), "*.example.com".to_string()])
+        );
+        assert_eq!(hotlink.allow_empty_referer, Some(true));
+        assert_eq!(hotlink.use_fallback, None);
+        assert!(got.providers[0].cors.is_none());
+        let cors = got.providers[1].cors.clone().expect("cors is missing");
+        assert_eq!(
+            cors.allowed_origins,
+            Vec::from(["https://editor.example.com".to_string()])
+        );
+        assert_eq!(
+            cors.allowed_methods,
+            Some(Vec::from(["GET".to_string(), "HEAD".to_string()]))
+        );
+        assert_eq!(
+            cors.allowed_headers,
+            Some(Vec::from(["Content-Type".to_string()]))
+        );
+        assert_eq!(
+            cors.exposed_headers,
+            Some(Vec::from(["Server-Timing".to_string()]))
+        );
+        assert_eq!(cors.max_age_seconds, None);
+        assert_eq!(cors.allow_credentials, Some(true));
+        assert!(got.providers[0].watermark.is_none());
+        let watermark = got.providers[1]
+            .watermark
+            .clone()
+            .expect("watermark is missing");
+        assert_eq!(watermark.path, "/qux/logo.png".to_string());
+        assert_eq!(watermark.position, Some("bottom-right".to_string()));
+        assert_eq!(watermark.margin, Some(16));
+        assert_eq!(watermark.opacity, Some(60));
+        assert_eq!(watermark.scale, Some(20));
+        assert_eq!(watermark.enforced, Some(true));
     }
 
     #[test]
@@ -138,6 +518,39 @@ mod tests {
         assert!(Config::from_reader(cfg.as_bytes()).is_err());
     }
 
+    #[test]
+    fn test_zero_queue_depth() {
+        let cfg = r#"
+            {
+              "port": 3000,
+              "bind_addr": "0.0.0.0",
+              "max_clients": 1024,
+              "client": {
+                "s3": {
+                  "aws_region": "ap-northeast-1"
+                },
+                "web": {
+                  "user_agent": "fanlin-rs/0.0.1",
+                  "timeout": 5
+                }
+              },
+              "providers": [
+                {
+                  "path": "foo",
+                  "src": "s3://local-test/images"
+                }
+              ],
+              "processing": {
+                "threads": 4,
+                "queue_depth": 0,
+                "queue_timeout_ms": 3000
+              }
+            }
+        "#;
+
+        assert!(Config::from_reader(cfg.as_bytes()).is_err());
+    }
+
     #[test]
     fn test_config_with_trailing_comma() {
         let cfg = r#"
@@ -204,5 +617,20 @@ mod tests {
         assert_eq!(got.client.s3.aws_endpoint_url, None);
         assert_eq!(got.client.s3.aws_access_key_id, None);
         assert_eq!(got.client.s3.aws_secret_access_key, None);
+        assert!(got.client.s3_clients.is_none());
+        assert!(got.client.gcs.is_none());
+        assert!(got.client.azure.is_none());
+        assert!(got.client.retry.is_none());
+        assert!(got.providers[0].hosts.is_none());
+        assert!(got.cache.is_none());
+        assert!(got.processing.is_none());
+        assert!(got.timeout.is_none());
+        assert!(got.rate_limit.is_none());
+        assert!(got.trusted_proxies.is_none());
+        assert!(got.cors.is_none());
+        assert!(got.telemetry.is_none());
+        assert!(got.health.is_none());
+        assert!(got.access_log.is_none());
+        assert!(got.text.is_none());
     }
 }
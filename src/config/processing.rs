@@ -0,0 +1,10 @@
+use serde::Deserialize;
+use std::num::NonZeroUsize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub threads: usize,
+    // A depth of zero would make a rendezvous channel, which sheds nearly every job.
+    pub queue_depth: NonZeroUsize,
+    pub queue_timeout_ms: u64,
+}
@@ -0,0 +1,17 @@
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub per_second: f64,
+    pub burst: f64,
+    pub costs: Option<Costs>,
+}
+
+// Tokens taken by a request using an operation, instead of one.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Costs {
+    pub avif: Option<f64>,
+    pub webp: Option<f64>,
+    pub blur: Option<f64>,
+    pub text: Option<f64>,
+}
@@ -0,0 +1,8 @@
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Rule {
+    pub pattern: String,
+    pub replacement: String,
+    pub query: Option<String>,
+}
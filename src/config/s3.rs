@@ -1,9 +1,14 @@
 use serde::Deserialize;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct Config {
     pub aws_region: String,
     pub aws_endpoint_url: Option<String>,
     pub aws_access_key_id: Option<String>,
     pub aws_secret_access_key: Option<String>,
+    pub aws_profile: Option<String>,
+    pub aws_role_arn: Option<String>,
+    pub aws_role_session_name: Option<String>,
+    pub aws_external_id: Option<String>,
+    pub aws_web_identity_token_file: Option<String>,
 }
@@ -0,0 +1,8 @@
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub request_ms: u64,
+    pub fetch_ms: Option<u64>,
+    pub process_ms: Option<u64>,
+}
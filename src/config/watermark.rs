@@ -0,0 +1,11 @@
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub path: String,
+    pub position: Option<String>,
+    pub margin: Option<u32>,
+    pub opacity: Option<u8>,
+    pub scale: Option<u8>,
+    pub enforced: Option<bool>,
+}
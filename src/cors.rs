@@ -0,0 +1,202 @@
+use super::config::cors;
+use axum::http::{header, HeaderMap, HeaderValue, Method};
+
+const DEFAULT_ALLOWED_METHODS: &str = "GET, HEAD";
+
+// https://developer.mozilla.org/en-US/docs/Web/HTTP/CORS
+#[derive(Clone, Debug)]
+pub struct Policy {
+    any_origin: bool,
+    allowed_origins: Vec<String>,
+    allowed_methods: HeaderValue,
+    allowed_headers: Option<HeaderValue>,
+    exposed_headers: Option<HeaderValue>,
+    max_age: Option<HeaderValue>,
+    allow_credentials: bool,
+}
+
+impl Policy {
+    pub fn new(cfg: cors::Config) -> Self {
+        let join = |list: Option<Vec<String>>| {
+            list.map(|v| {
+                HeaderValue::from_str(v.join(", ").as_str())
+                    .expect("failed to make a cors header value")
+            })
+        };
+        let allow_credentials = cfg.allow_credentials.is_some_and(|v| v);
+        let any_origin = cfg.allowed_origins.iter().any(|v| v == "*");
+        if any_origin && allow_credentials {
+            panic!("cors allow_credentials cannot be used with any origin");
+        }
+        Self {
+            any_origin,
+            allowed_origins: cfg
+                .allowed_origins
+                .iter()
+                .map(|v| v.trim_end_matches('/').to_ascii_lowercase())
+                .collect(),
+            allowed_methods: join(cfg.allowed_methods)
+                .unwrap_or(HeaderValue::from_static(DEFAULT_ALLOWED_METHODS)),
+            allowed_headers: join(cfg.allowed_headers),
+            exposed_headers: join(cfg.exposed_headers),
+            max_age: cfg.max_age_seconds.map(HeaderValue::from),
+            allow_credentials,
+        }
+    }
+
+    // https://developer.mozilla.org/en-US/docs/Glossary/Preflight_request
+    pub fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+        method == Method::OPTIONS
+            && headers.contains_key(header::ORIGIN)
+            && headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    pub fn preflight(&self, request_headers: &HeaderMap) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        append_vary(&mut headers, "Origin");
+        append_vary(&mut headers, "Access-Control-Request-Method");
+        append_vary(&mut headers, "Access-Control-Request-Headers");
+        if !self.allow_origin(request_headers.get(header::ORIGIN), &mut headers) {
+            return headers;
+        }
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            self.allowed_methods.clone(),
+        );
+        if let Some(v) = &self.allowed_headers {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, v.clone());
+        }
+        if let Some(v) = &self.max_age {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, v.clone());
+        }
+        headers
+    }
+
+    pub fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        append_vary(headers, "Origin");
+        if !self.allow_origin(origin, headers) {
+            return;
+        }
+        if let Some(v) = &self.exposed_headers {
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, v.clone());
+        }
+    }
+
+    fn allow_origin(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) -> bool {
+        let Some(origin) = origin else {
+            return false;
+        };
+        if self.any_origin {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                HeaderValue::from_static("*"),
+            );
+            return true;
+        }
+        let allowed = origin
+            .to_str()
+            .is_ok_and(|v| self.allowed_origins.contains(&v.to_ascii_lowercase()));
+        if !allowed {
+            return false;
+        }
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        true
+    }
+}
+
+// Keeps Vary a single comma separated value, so that Accept set by the handler stays.
+fn append_vary(headers: &mut HeaderMap, name: &'static str) {
+    let mut values: Vec<String> = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect();
+    if values
+        .iter()
+        .any(|v| v == "*" || v.eq_ignore_ascii_case(name))
+    {
+        return;
+    }
+    values.push(name.to_string());
+    if let Ok(v) = HeaderValue::from_str(values.join(", ").as_str()) {
+        headers.insert(header::VARY, v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let policy = Policy::new(cors::Config {
+            allowed_origins: Vec::from(["https://Editor.example.com/".to_string()]),
+            allowed_methods: None,
+            allowed_headers: Some(Vec::from(["Content-Type".to_string()])),
+            exposed_headers: Some(Vec::from(["Server-Timing".to_string()])),
+            max_age_seconds: Some(600),
+            allow_credentials: Some(true),
+        });
+        let origin = HeaderValue::from_static("https://editor.example.com");
+        let mut headers = HeaderMap::new();
+        headers.insert(header::VARY, HeaderValue::from_static("Accept"));
+        policy.apply(Some(&origin), &mut headers);
+        assert_eq!(headers[header::VARY], "Accept, Origin");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_EXPOSE_HEADERS],
+            "Server-Timing"
+        );
+
+        let mut headers = HeaderMap::new();
+        let other = HeaderValue::from_static("https://evil.test");
+        policy.apply(Some(&other), &mut headers);
+        assert_eq!(headers[header::VARY], "Origin");
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(header::ORIGIN, origin.clone());
+        request_headers.insert(
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            HeaderValue::from_static("GET"),
+        );
+        assert!(Policy::is_preflight(&Method::OPTIONS, &request_headers));
+        assert!(!Policy::is_preflight(&Method::GET, &request_headers));
+        let headers = policy.preflight(&request_headers);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET, HEAD");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "Content-Type"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        let any = Policy::new(cors::Config {
+            allowed_origins: Vec::from(["*".to_string()]),
+            allowed_methods: Some(Vec::from(["GET".to_string()])),
+            allowed_headers: None,
+            exposed_headers: None,
+            max_age_seconds: None,
+            allow_credentials: None,
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert(header::VARY, HeaderValue::from_static("accept, origin"));
+        any.apply(Some(&other), &mut headers);
+        assert_eq!(headers[header::VARY], "accept, origin");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+        let mut headers = HeaderMap::new();
+        any.apply(None, &mut headers);
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -1,31 +1,162 @@
+use super::access_log;
 use super::config;
 use super::content;
+use super::cors;
+use super::health;
 use super::infra;
+use super::metrics;
+use super::pool;
 use super::query;
+use super::rate_limit;
+use super::text;
+use super::watermark;
+use base64::prelude::{Engine, BASE64_STANDARD};
 use image::{
     codecs::{avif, gif, jpeg, png},
     imageops::{overlay, FilterType},
     AnimationDecoder, DynamicImage, Frame, ImageBuffer, ImageDecoder, ImageFormat, ImageReader,
     Limits, Rgba, RgbaImage,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use tracing::Instrument;
+
+const PLACEHOLDER_SIZE: u32 = 16;
+const MAX_RASTER_SIZE: u32 = 4096;
+const DEFAULT_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
 
 #[derive(Debug)]
 pub struct State {
-    router: matchit::Router<Provider>,
+    routes: Routes,
     client: infra::Client,
-    fallback_images: HashMap<String, Vec<u8>>,
+    fallback_images: HashMap<(String, String), bytes::Bytes>,
+    watermark_images: HashMap<(String, String), std::sync::Arc<RgbaImage>>,
+    replicating: std::sync::Arc<std::sync::Mutex<HashSet<String>>>,
+    fonts: Option<text::Fonts>,
     fallback_path: String,
     cmyk2rgb: Option<CMYK2RGB>,
     use_embedded_profile: bool,
+    originals: Option<infra::cache::Cache>,
+    pool: pool::Pool,
+    budget: Budget,
+    rate_limiter: Option<rate_limit::Limiter>,
+    trusted_proxies: rate_limit::TrustedProxies,
+    cors: Option<cors::Policy>,
+    metrics: metrics::Metrics,
+    health: health::Health,
+    access_log: access_log::Logger,
+}
+
+// Time limits of a request and its stages, where each stage is also bounded by the request.
+#[derive(Debug)]
+struct Budget {
+    request: std::time::Duration,
+    fetch: std::time::Duration,
+    process: std::time::Duration,
+}
+
+// Providers declaring hosts are looked up by the Host header first, and the others serve any host.
+#[derive(Debug, Default)]
+struct Routes {
+    hosts: Vec<(String, matchit::Router<Provider>)>,
+    any: matchit::Router<Provider>,
+    all: Vec<Provider>,
 }
 
 #[derive(Clone, Debug)]
 struct Provider {
     path: String,
-    src: axum::http::uri::Uri,
+    hosts: Vec<String>,
+    sources: Vec<Source>,
     fallback_path: String,
     success_even_no_content: bool,
+    s3_client: Option<String>,
+    replicate_to: Option<axum::http::uri::Uri>,
+    rewrites: Vec<Rewrite>,
+    rate_limiter: Option<std::sync::Arc<rate_limit::Limiter>>,
+    hotlink: Option<Hotlink>,
+    cors: Option<cors::Policy>,
+    watermark: Option<watermark::Settings>,
+}
+
+#[derive(Clone, Debug)]
+struct Hotlink {
+    allowed_referers: Vec<String>,
+    allow_empty_referer: bool,
+    use_fallback: bool,
+}
+
+// How to answer a request referred from a site, by the hotlink protection of its provider.
+#[derive(Debug, PartialEq)]
+pub enum Referral {
+    Allowed,
+    Forbidden,
+    Placeholder,
+}
+
+#[derive(Clone, Debug)]
+struct Rewrite {
+    pattern: regex::Regex,
+    replacement: String,
+    query: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Source {
+    uri: axum::http::uri::Uri,
+    circuit_breaker: Option<std::sync::Arc<infra::circuit_breaker::CircuitBreaker>>,
+}
+
+#[derive(Debug)]
+pub struct Original {
+    pub data: bytes::Bytes,
+    // Index of the source in a provider that served it.
+    pub source: usize,
+    pub location: String,
+    pub cache: Option<CacheStatus>,
+}
+
+// A preview of an original for lazy loading, as answered to `?lqip=true`.
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct Placeholder {
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: String,
+    pub color: String,
+    pub data_uri: String,
+}
+
+// Properties of an original, as answered to `?info=true`.
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct Info {
+    pub format: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub color_type: Option<String>,
+    pub bit_depth: Option<u16>,
+    pub frames: Option<usize>,
+    pub icc_profile: bool,
+    pub cmyk: Option<&'static str>,
+    pub bytes: usize,
+}
+
+// How the originals cache served an original, if it is enabled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheStatus {
+    Hit,
+    Revalidated,
+    Miss,
+}
+
+// An original relayed from its origin as it is, or buffered to be processed.
+#[derive(Debug)]
+pub enum Fetched {
+    Passthrough {
+        mime_type: &'static str,
+        body: axum::body::Body,
+        source: usize,
+        location: String,
+    },
+    Buffered(Original),
 }
 
 #[derive(Debug)]
@@ -33,30 +164,129 @@ struct CMYK2RGB {
     t: lcms2::Transform<[u8; 4], [u8; 3], lcms2::ThreadContext, lcms2::DisallowCache>,
 }
 
+impl Original {
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        dimensions(&self.data)
+    }
+}
+
+// Reads only the header of an image.
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let cursor = std::io::Cursor::new(data);
+    ImageReader::new(cursor)
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hit => "hit",
+            Self::Revalidated => "revalidated",
+            Self::Miss => "miss",
+        }
+    }
+}
+
 impl State {
     pub fn new(providers: Vec<config::Provider>, client: infra::Client) -> Self {
-        let router = Self::make_router(providers);
-        let fallback_images: HashMap<String, Vec<u8>> = HashMap::new();
+        let routes = Self::make_routes(providers, &client);
+        let fallback_images: HashMap<(String, String), bytes::Bytes> = HashMap::new();
+        let watermark_images = HashMap::new();
+        let replicating = std::sync::Arc::new(std::sync::Mutex::new(HashSet::new()));
+        let fonts = None;
         let fallback_path = "".to_string();
         let cmyk2rgb = None;
         let use_embedded_profile = false;
+        let originals = None;
+        let pool = pool::Pool::new(None);
+        let budget = Budget {
+            request: DEFAULT_REQUEST_TIMEOUT,
+            fetch: DEFAULT_REQUEST_TIMEOUT,
+            process: DEFAULT_REQUEST_TIMEOUT,
+        };
+        let rate_limiter = None;
+        let trusted_proxies = rate_limit::TrustedProxies::default();
+        let cors = None;
+        let metrics = metrics::Metrics::new();
+        let health = health::Health::new();
+        let access_log = access_log::Logger::default();
         Self {
-            router,
+            routes,
             client,
             fallback_images,
+            watermark_images,
+            replicating,
+            fonts,
             fallback_path,
             cmyk2rgb,
             use_embedded_profile,
+            originals,
+            pool,
+            budget,
+            rate_limiter,
+            trusted_proxies,
+            cors,
+            metrics,
+            health,
+            access_log,
         }
     }
 
-    fn make_router(providers: Vec<config::Provider>) -> matchit::Router<Provider> {
-        let mut router = matchit::Router::new();
+    fn make_routes(providers: Vec<config::Provider>, client: &infra::Client) -> Routes {
+        let mut routes = Routes::default();
         for p in providers.iter() {
-            let src = p
+            let sources: Vec<Source> = p
                 .src
-                .parse::<axum::http::uri::Uri>()
-                .expect("failed to parse a provider src as URI");
+                .to_vec()
+                .iter()
+                .map(|src| Source {
+                    uri: src
+                        .parse::<axum::http::uri::Uri>()
+                        .expect("failed to parse a provider src as URI"),
+                    circuit_breaker: p.circuit_breaker.clone().map(|c| {
+                        std::sync::Arc::new(infra::circuit_breaker::CircuitBreaker::new(c))
+                    }),
+                })
+                .collect();
+            if sources.is_empty() {
+                panic!("a provider requires at least one src");
+            }
+            if client.gcs.is_none() && sources.iter().any(|v| v.uri.scheme_str() == Some("gs")) {
+                panic!("a provider refers to gcs but the client is not configured");
+            }
+            if client.azure.is_none()
+                && sources
+                    .iter()
+                    .any(|v| matches!(v.uri.scheme_str(), Some("az" | "azblob")))
+            {
+                panic!("a provider refers to azure but the client is not configured");
+            }
+            client
+                .s3_client(p.s3_client.as_deref())
+                .expect("a provider refers to an undefined s3 client");
+            let replicate_to = p.replicate_to.as_ref().map(|v| {
+                let uri = v
+                    .parse::<axum::http::uri::Uri>()
+                    .expect("failed to parse a provider replicate_to as URI");
+                if uri.scheme_str() != Some("s3") {
+                    panic!("a provider replicate_to must be an s3 URI");
+                }
+                uri
+            });
+            let rewrites = p
+                .rewrites
+                .iter()
+                .flatten()
+                .map(|r| Rewrite {
+                    pattern: regex::Regex::new(r.pattern.as_str())
+                        .expect("failed to compile a rewrite pattern"),
+                    replacement: r.replacement.clone(),
+                    query: r.query.clone(),
+                })
+                .collect();
             let path = p
                 .path
                 .trim_start_matches("/")
@@ -69,17 +299,41 @@ impl State {
             prefix.push_str("/{*p}");
             let fallback_path = p.fallback_path.clone().map_or("".to_string(), |v| v);
             let success_even_no_content = p.success_even_no_content.is_some_and(|v| v);
+            let hosts: Vec<String> = p
+                .hosts
+                .iter()
+                .flatten()
+                .map(|v| normalize_host(v))
+                .collect();
             let provider = Provider {
                 path,
-                src,
+                hosts: hosts.clone(),
+                sources,
                 fallback_path,
                 success_even_no_content,
+                s3_client: p.s3_client.clone(),
+                replicate_to,
+                rewrites,
+                rate_limiter: p
+                    .rate_limit
+                    .clone()
+                    .map(|c| std::sync::Arc::new(rate_limit::Limiter::new(c))),
+                hotlink: p.hotlink.as_ref().map(|c| Hotlink {
+                    allowed_referers: c
+                        .allowed_referers
+                        .iter()
+                        .map(|v| normalize_host(v))
+                        .collect(),
+                    allow_empty_referer: c.allow_empty_referer.is_some_and(|v| v),
+                    use_fallback: c.use_fallback.is_some_and(|v| v),
+                }),
+                cors: p.cors.clone().map(cors::Policy::new),
+                watermark: p.watermark.as_ref().map(watermark::Settings::new),
             };
-            router
-                .insert(prefix, provider)
-                .expect("failed to make router with providers");
+            routes.insert(&hosts, prefix, provider);
         }
-        router
+        routes.sort();
+        routes
     }
 
     pub async fn create_cmyk_to_rgb_converter<P: AsRef<std::path::Path>>(&mut self, path: P) {
@@ -87,12 +341,21 @@ impl State {
             Ok(d) => d,
             Err(e) => {
                 tracing::warn!("failed to load an icc profile; {e:?}");
+                self.health
+                    .set_cmyk_profile(Err(format!("failed to load an icc profile; {e}")));
                 return;
             }
         };
         match CMYK2RGB::with_icc_profile(icc.as_slice()) {
-            Some(t) => self.cmyk2rgb = Some(t),
-            None => tracing::warn!("failed to create a transform object"),
+            Some(t) => {
+                self.cmyk2rgb = Some(t);
+                self.health.set_cmyk_profile(Ok(()));
+            }
+            None => {
+                tracing::warn!("failed to create a transform object");
+                let message = "failed to create a transform object".to_string();
+                self.health.set_cmyk_profile(Err(message));
+            }
         }
     }
 
@@ -100,133 +363,815 @@ impl State {
         self.use_embedded_profile = true;
     }
 
+    pub fn enable_originals_cache(&mut self, cfg: config::cache::Config) {
+        self.originals = Some(infra::cache::Cache::new(cfg));
+    }
+
+    pub fn configure_processing_pool(&mut self, cfg: config::processing::Config) {
+        self.pool = pool::Pool::new(Some(cfg));
+    }
+
+    pub fn configure_timeouts(&mut self, cfg: config::timeout::Config) {
+        let request = std::time::Duration::from_millis(cfg.request_ms);
+        let stage = |v: Option<u64>| v.map_or(request, std::time::Duration::from_millis);
+        self.budget = Budget {
+            request,
+            fetch: stage(cfg.fetch_ms),
+            process: stage(cfg.process_ms),
+        };
+    }
+
+    pub fn enable_rate_limit(&mut self, cfg: config::rate_limit::Config) {
+        self.rate_limiter = Some(rate_limit::Limiter::new(cfg));
+    }
+
+    pub fn trust_proxies(&mut self, list: &[String]) {
+        self.trusted_proxies = rate_limit::TrustedProxies::new(list);
+    }
+
+    pub fn enable_cors(&mut self, cfg: config::cors::Config) {
+        self.cors = Some(cors::Policy::new(cfg));
+    }
+
+    // The policy of a provider overrides the global one as a whole.
+    pub fn cors_policy(&self, host: Option<&str>, req_path: &str) -> Option<&cors::Policy> {
+        self.routes
+            .at(host, req_path)
+            .and_then(|v| v.cors.as_ref())
+            .or(self.cors.as_ref())
+    }
+
+    pub fn configure_health_checks(&mut self, cfg: config::health::Config) {
+        self.health.configure(cfg);
+    }
+
+    pub async fn readiness(&self) -> health::Report {
+        self.health.report(|| self.probe_providers()).await
+    }
+
+    async fn probe_providers(&self) -> Vec<health::ProviderCheck> {
+        let timeout = self.health.probe_timeout();
+        let probing = self.routes.all.iter().map(|provider| async move {
+            let sources = provider.sources.iter().enumerate().map(|(index, source)| {
+                let probing = self.probe(provider, &source.uri);
+                async move {
+                    let result = match tokio::time::timeout(timeout, probing).await {
+                        Ok(v) => v,
+                        Err(_) => Err(format!("timed out in {timeout:?}")),
+                    };
+                    if let Err(err) = &result {
+                        tracing::warn!("failed to probe a source; {} {err}", source.uri);
+                    }
+                    // Sources are told by their indices, so that bucket names and origins are not exposed.
+                    health::SourceCheck {
+                        index,
+                        check: health::Check::from(result),
+                    }
+                }
+            });
+            let sources = futures_util::future::join_all(sources).await;
+            health::ProviderCheck {
+                path: provider.path.clone(),
+                hosts: provider.hosts.clone(),
+                ok: sources.iter().any(|v| v.check.ok),
+                sources,
+            }
+        });
+        futures_util::future::join_all(probing).await
+    }
+
+    // Sources other than S3, HTTP and local files are regarded as reachable without probing.
+    async fn probe(&self, provider: &Provider, uri: &axum::http::uri::Uri) -> Result<(), String> {
+        match uri.scheme().map_or("", |v| v.as_str()) {
+            "s3" => {
+                let bucket = uri.host().ok_or("bucket of a src is missing")?;
+                self.client
+                    .s3_client(provider.s3_client.as_deref())
+                    .ok_or("undefined s3 client")?
+                    .head_bucket(bucket)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            "http" | "https" => self
+                .client
+                .web
+                .probe(uri.to_string().as_str())
+                .await
+                .map_err(|e| e.to_string()),
+            "file" => {
+                let local_path = build_local_path(uri, "", "").map_err(|e| e.to_string())?;
+                self.client
+                    .file
+                    .probe(local_path)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn configure_access_log(&mut self, cfg: config::access_log::Config) {
+        self.access_log = access_log::Logger::new(Some(cfg));
+    }
+
+    pub fn access_log(&self) -> &access_log::Logger {
+        &self.access_log
+    }
+
+    pub fn metrics(&self) -> &metrics::Metrics {
+        &self.metrics
+    }
+
+    // Names a provider by its path in metrics.
+    pub fn provider_label(&self, host: Option<&str>, req_path: &str) -> &str {
+        self.routes
+            .at(host, req_path)
+            .map_or(metrics::NO_PROVIDER, |v| v.path.as_str())
+    }
+
+    pub fn client_ip(
+        &self,
+        peer: std::net::IpAddr,
+        headers: &axum::http::HeaderMap,
+    ) -> std::net::IpAddr {
+        self.trusted_proxies.client_ip(peer, headers)
+    }
+
+    // The global limit is applied first, and then the one of the provider.
+    pub fn check_rate_limit(
+        &self,
+        host: Option<&str>,
+        req_path: &str,
+        client: std::net::IpAddr,
+        params: &query::Query,
+        content: content::Format,
+    ) -> Result<(), std::time::Duration> {
+        let global = self.rate_limiter.as_ref();
+        if let Some(limiter) = global {
+            limiter.try_acquire(client, params, content)?;
+        }
+        let provider = self
+            .routes
+            .at(host, req_path)
+            .and_then(|v| v.rate_limiter.as_ref());
+        if let Some(limiter) = provider {
+            if let Err(wait) = limiter.try_acquire(client, params, content) {
+                // A request refused by its provider does not spend the global budget.
+                if let Some(global) = global {
+                    global.refund(client, params, content);
+                }
+                return Err(wait);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn request_timeout(&self) -> std::time::Duration {
+        self.budget.request
+    }
+
+    pub fn fetch_budget(&self, elapsed: std::time::Duration) -> std::time::Duration {
+        let left = self.budget.request.saturating_sub(elapsed);
+        self.budget.fetch.min(left)
+    }
+
+    pub fn process_budget(&self, elapsed: std::time::Duration) -> std::time::Duration {
+        let left = self.budget.request.saturating_sub(elapsed);
+        self.budget.process.min(left)
+    }
+
+    // Errors are given as strings because Box<dyn Error> cannot be sent back from the pool.
+    pub async fn process_image_in_pool(
+        self: &std::sync::Arc<Self>,
+        original: bytes::Bytes,
+        params: query::Query,
+        content: content::Format,
+        stamp: Option<watermark::Stamp>,
+    ) -> Result<Result<(&'static str, bytes::Bytes), String>, pool::Rejected> {
+        let state = self.clone();
+        // Spans on worker threads are parented explicitly since they do not follow the task.
+        let span = tracing::info_span!("process");
+        self.pool
+            .run(move || {
+                let _entered = span.enter();
+                let caption = state.caption(&params)?;
+                state
+                    .process_image(
+                        &original,
+                        &params,
+                        content,
+                        stamp.as_ref(),
+                        caption.as_ref(),
+                    )
+                    .map_err(|e| e.to_string())
+            })
+            .await
+    }
+
+    pub async fn make_placeholder_in_pool(
+        self: &std::sync::Arc<Self>,
+        original: bytes::Bytes,
+    ) -> Result<Result<Placeholder, String>, pool::Rejected> {
+        let state = self.clone();
+        let span = tracing::info_span!("process");
+        self.pool
+            .run(move || {
+                let _entered = span.enter();
+                state.placeholder(&original).map_err(|e| e.to_string())
+            })
+            .await
+    }
+
+    pub async fn inspect_image_in_pool(
+        self: &std::sync::Arc<Self>,
+        original: bytes::Bytes,
+    ) -> Result<Result<Info, String>, pool::Rejected> {
+        let span = tracing::info_span!("inspect");
+        self.pool
+            .run(move || {
+                let _entered = span.enter();
+                inspect_image(&original).map_err(|e| e.to_string())
+            })
+            .await
+    }
+
     pub async fn with_fallback(
         &mut self,
         path: &Option<String>,
         providers: &[config::Provider],
     ) -> Result<(), Box<dyn std::error::Error>> {
+        let result = self.load_fallback_images(path, providers).await;
+        let status = match &result {
+            Ok(missing) if missing.is_empty() => Ok(()),
+            Ok(missing) => Err(format!("fallback images not found; {}", missing.join(", "))),
+            Err(err) => Err(err.to_string()),
+        };
+        self.health.set_fallback(status);
+        result.map(|_| ())
+    }
+
+    // Gives the paths of fallback images that are not found.
+    async fn load_fallback_images(
+        &mut self,
+        path: &Option<String>,
+        providers: &[config::Provider],
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut missing = Vec::new();
         if let Some(path) = path {
-            if let Some(img) = self.get_image(path).await? {
-                let _ = self.fallback_images.insert(path.clone(), img);
-                self.fallback_path = path.clone();
+            match self.get_image(None, path).await? {
+                Some(img) => {
+                    let key = ("".to_string(), path.clone());
+                    let _ = self.fallback_images.insert(key, img.data);
+                    self.fallback_path = path.clone();
+                }
+                None => missing.push(path.clone()),
             }
         }
         for provider in providers.iter() {
             if let Some(path) = &provider.fallback_path {
-                if let Some(img) = self.get_image(path.as_str()).await? {
-                    let _ = self.fallback_images.insert(path.clone(), img);
+                // A fallback image of a provider with hosts is looked up under its first host.
+                let host = provider
+                    .hosts
+                    .iter()
+                    .flatten()
+                    .next()
+                    .map_or("".to_string(), |v| normalize_host(v));
+                let img = match self.routes.at_pattern(host.as_str(), path) {
+                    Some(p) => self.get_image_from(p, path, false).await?,
+                    None => None,
+                };
+                match img {
+                    Some(img) => {
+                        let _ = self.fallback_images.insert((host, path.clone()), img.data);
+                    }
+                    None => missing.push(path.clone()),
                 }
             }
         }
+        Ok(missing)
+    }
+
+    // Loads the logos of watermarks through providers, as fallback images are.
+    pub async fn with_watermarks(
+        &mut self,
+        providers: &[config::Provider],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for provider in providers.iter() {
+            let Some(cfg) = &provider.watermark else {
+                continue;
+            };
+            let host = provider
+                .hosts
+                .iter()
+                .flatten()
+                .next()
+                .map_or("".to_string(), |v| normalize_host(v));
+            let img = match self.routes.at_pattern(host.as_str(), &cfg.path) {
+                Some(p) => self.get_image_from(p, &cfg.path, false).await?,
+                None => None,
+            };
+            let img = img.ok_or_else(|| format!("watermark image not found; {}", cfg.path))?;
+            let logo = image::load_from_memory(&img.data)?.into_rgba8();
+            self.watermark_images
+                .insert((host, cfg.path.clone()), std::sync::Arc::new(logo));
+        }
         Ok(())
     }
 
-    pub fn fallback(
+    // Gives a watermark to stamp, or an error if one is asked for but its logo is not loaded.
+    pub fn watermark(
         &self,
+        host: Option<&str>,
         req_path: &str,
         params: &query::Query,
+    ) -> Result<Option<watermark::Stamp>, String> {
+        let Some(provider) = self.routes.at(host, req_path) else {
+            return Ok(None);
+        };
+        let Some(settings) = &provider.watermark else {
+            return Ok(None);
+        };
+        let key = (
+            provider.hosts.first().cloned().unwrap_or_default(),
+            settings.path.clone(),
+        );
+        match self.watermark_images.get(&key) {
+            Some(logo) => Ok(settings.stamp(logo.clone(), params)),
+            None if !settings.applies(params) => Ok(None),
+            None => Err(format!("watermark image is not loaded; {}", settings.path)),
+        }
+    }
+
+    pub fn load_fonts(
+        &mut self,
+        cfg: &config::text::Config,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.fonts = Some(text::Fonts::load(cfg)?);
+        Ok(())
+    }
+
+    // Gives the font of a text to draw, or an error if it asks for a font which is not loaded.
+    pub fn font(&self, params: &query::Query) -> Result<Option<&ab_glyph::FontVec>, String> {
+        if params.text().is_none() {
+            return Ok(None);
+        }
+        let Some(fonts) = &self.fonts else {
+            return Err("fonts are not loaded".to_string());
+        };
+        match fonts.get(params.font()) {
+            Some(font) => Ok(Some(font)),
+            None => Err(format!(
+                "font not found; {}",
+                params.font().unwrap_or_default()
+            )),
+        }
+    }
+
+    // Glyphs are rasterized here, so this is called on the pool.
+    fn caption(&self, params: &query::Query) -> Result<Option<text::Caption>, String> {
+        let font = self.font(params)?;
+        Ok(font
+            .zip(params.text())
+            .map(|(font, text)| text::Caption::new(font, text, params)))
+    }
+
+    fn fallback_image(&self, host: Option<&str>, req_path: &str) -> Option<bytes::Bytes> {
+        let global = ("".to_string(), self.fallback_path.clone());
+        let provider = self.routes.at(host, req_path).and_then(|p| {
+            let key = (
+                p.hosts.first().cloned().unwrap_or_default(),
+                p.fallback_path.clone(),
+            );
+            self.fallback_images.get(&key)
+        });
+        provider
+            .or_else(|| self.fallback_images.get(&global))
+            .cloned()
+    }
+
+    // Fallback images are processed on the pool too, so that they are shed along with others.
+    pub async fn fallback_in_pool(
+        self: &std::sync::Arc<Self>,
+        host: Option<&str>,
+        req_path: &str,
+        params: query::Query,
         content: content::Format,
-    ) -> Result<(&'static str, Vec<u8>), Box<dyn std::error::Error>> {
-        match self.router.at(req_path) {
-            Ok(matched) => {
-                let provider = matched.value;
-                match self.fallback_images.get(&provider.fallback_path) {
-                    Some(img) => self.process_image(img, params, content),
-                    None => match self.fallback_images.get(&self.fallback_path) {
-                        Some(img) => self.process_image(img, params, content),
-                        None => Err(Box::from("fallback image uninitialized")),
-                    },
-                }
-            }
-            Err(_) => match self.fallback_images.get(&self.fallback_path) {
-                Some(img) => self.process_image(img, params, content),
-                None => Err(Box::from("fallback image uninitialized")),
-            },
+    ) -> Result<Result<(&'static str, bytes::Bytes), String>, pool::Rejected> {
+        let Some(original) = self.fallback_image(host, req_path) else {
+            return Ok(Err("fallback image uninitialized".to_string()));
+        };
+        let state = self.clone();
+        let span = tracing::info_span!("fallback");
+        self.pool
+            .run(move || {
+                let _entered = span.enter();
+                state
+                    .process_image(&original, &params, content, None, None)
+                    .map_err(|e| e.to_string())
+            })
+            .await
+    }
+
+    pub fn treat_as_success_even_no_content(&self, host: Option<&str>, req_path: &str) -> bool {
+        self.routes
+            .at(host, req_path)
+            .is_some_and(|v| v.success_even_no_content)
+    }
+
+    pub fn check_referral(
+        &self,
+        host: Option<&str>,
+        req_path: &str,
+        headers: &axum::http::HeaderMap,
+    ) -> Referral {
+        let Some(hotlink) = self
+            .routes
+            .at(host, req_path)
+            .and_then(|v| v.hotlink.as_ref())
+        else {
+            return Referral::Allowed;
+        };
+        let allowed = match referring_host(headers) {
+            Some(Some(referer)) => hotlink
+                .allowed_referers
+                .iter()
+                .any(|v| match_host(v, referer.as_str())),
+            Some(None) => false,
+            None => hotlink.allow_empty_referer,
+        };
+        match (allowed, hotlink.use_fallback) {
+            (true, _) => Referral::Allowed,
+            (false, true) => Referral::Placeholder,
+            (false, false) => Referral::Forbidden,
         }
     }
 
-    pub fn treat_as_success_even_no_content(&self, req_path: &str) -> bool {
-        self.router
-            .at(req_path)
-            .is_ok_and(|v| v.value.success_even_no_content)
+    pub fn default_query(&self, host: Option<&str>, req_path: &str) -> Option<query::Query> {
+        let provider = self.routes.at(host, req_path)?;
+        let query_string = provider.default_query(req_path)?;
+        // https://docs.rs/serde_urlencoded/latest/serde_urlencoded/fn.from_str.html
+        match serde_urlencoded::from_str(query_string.as_str()) {
+            Ok(q) => Some(q),
+            Err(err) => {
+                tracing::warn!("failed to parse a default query; {query_string} {err:?}");
+                None
+            }
+        }
     }
 
     pub async fn get_image(
         &self,
+        host: Option<&str>,
         req_path: &str,
-    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
-        // https://docs.rs/matchit/latest/matchit/index.html
-        // https://docs.rs/matchit/latest/matchit/struct.Router.html
-        match self.router.at(req_path) {
-            Ok(matched) => {
-                let provider = matched.value;
-                let prefix = provider.path.as_str();
-                let uri = &provider.src;
-                match uri.scheme().map_or("", |v| v.as_str()) {
-                    "s3" => {
-                        let (bucket, key) = build_bucket_and_object_key(uri, prefix, req_path)?;
-                        self.client.s3.get_object(bucket, key).await
-                    }
-                    "http" | "https" => {
-                        let url = build_url(uri, prefix, req_path)?;
-                        self.client.web.get(url).await
+    ) -> Result<Option<Original>, Box<dyn std::error::Error>> {
+        match self.routes.at(host, req_path) {
+            Some(provider) => self.get_image_from(provider, req_path, true).await,
+            None => Ok(None),
+        }
+    }
+
+    // Originals loaded at startup, such as fallback images, are not replicated.
+    async fn get_image_from(
+        &self,
+        provider: &Provider,
+        req_path: &str,
+        replicating: bool,
+    ) -> Result<Option<Original>, Box<dyn std::error::Error>> {
+        let key = provider.cache_key(req_path);
+        if let Some(original) = self.get_cached_image(provider, req_path, &key).await {
+            return Ok(Some(original));
+        }
+        let mut last_err = None;
+        for (i, source) in provider.sources.iter().enumerate() {
+            let src = source.uri.to_string();
+            let fetching = self.fetch(provider, &source.uri, req_path, None);
+            match self.with_breaker(source, fetching).await {
+                Ok(Some(infra::Revalidated::Modified(object))) => {
+                    log_source(req_path, i, &src);
+                    if replicating && matches!(source.uri.scheme_str(), Some("http" | "https")) {
+                        self.replicate(provider, req_path, &object);
                     }
-                    "file" => {
-                        let local_path = build_local_path(uri, prefix, req_path)?;
-                        self.client.file.read(local_path).await
+                    if let Some(cache) = &self.originals {
+                        cache.insert(key, i, object.clone());
                     }
-                    _ => Ok(None),
+                    return Ok(Some(Original {
+                        data: object.data,
+                        source: i,
+                        location: provider.location(&source.uri, req_path),
+                        cache: self.originals.as_ref().map(|_| CacheStatus::Miss),
+                    }));
+                }
+                // An error from an earlier source does not make a missing object a server error.
+                Ok(Some(infra::Revalidated::NotModified)) | Ok(None) => last_err = None,
+                Err(err) => {
+                    tracing::warn!("failed to fetch from a source; {req_path} {src} {err:?}");
+                    // Box<dyn Error> is not Send, so it must not be held across the next await.
+                    last_err = Some(format!("{src} {err}"));
+                }
+            }
+        }
+        match last_err {
+            Some(err) => Err(Box::from(err)),
+            None => Ok(None),
+        }
+    }
+
+    // Serves a cached original, revalidating it with the source that served it once it goes stale.
+    async fn get_cached_image(
+        &self,
+        provider: &Provider,
+        req_path: &str,
+        key: &str,
+    ) -> Option<Original> {
+        let cache = self.originals.as_ref()?;
+        let entry = cache.get(key)?;
+        let index = entry.source;
+        let source = provider.sources.get(index)?;
+        let location = provider.location(&source.uri, req_path);
+        if cache.is_fresh(&entry) {
+            return Some(Original {
+                data: entry.object.data,
+                source: index,
+                location,
+                cache: Some(CacheStatus::Hit),
+            });
+        }
+        let fetching = self.fetch(provider, &source.uri, req_path, Some(&entry.object));
+        let result = self.with_breaker(source, fetching).await;
+        match result {
+            Ok(Some(infra::Revalidated::NotModified)) => {
+                cache.touch(key);
+                Some(Original {
+                    data: entry.object.data,
+                    source: index,
+                    location,
+                    cache: Some(CacheStatus::Revalidated),
+                })
+            }
+            Ok(Some(infra::Revalidated::Modified(object))) => {
+                if matches!(source.uri.scheme_str(), Some("http" | "https")) {
+                    self.replicate(provider, req_path, &object);
+                }
+                cache.insert(key.to_string(), entry.source, object.clone());
+                Some(Original {
+                    data: object.data,
+                    source: index,
+                    location,
+                    cache: Some(CacheStatus::Miss),
+                })
+            }
+            Ok(None) => {
+                cache.remove(key);
+                None
+            }
+            Err(err) => {
+                let src = &source.uri;
+                tracing::warn!("failed to revalidate a cached original; {req_path} {src} {err:?}");
+                cache.remove(key);
+                None
+            }
+        }
+    }
+
+    fn replicate(&self, provider: &Provider, req_path: &str, object: &infra::Object) {
+        let Some(uri) = &provider.replicate_to else {
+            return;
+        };
+        let Some(cli) = self.client.s3_client(provider.s3_client.as_deref()) else {
+            return;
+        };
+        let target = provider.target(req_path);
+        let key = target.and_then(|(prefix, path)| build_bucket_and_object_key(uri, prefix, &path));
+        let (bucket, key) = match key {
+            Ok(v) => v,
+            Err(err) => {
+                tracing::warn!("failed to build a replica key; {req_path} {err:?}");
+                return;
+            }
+        };
+        // Concurrent misses of the same original upload it only once.
+        let replica = format!("s3://{bucket}/{key}");
+        let mut replicating = self.replicating.lock().unwrap_or_else(|e| e.into_inner());
+        if !replicating.insert(replica.clone()) {
+            return;
+        }
+        drop(replicating);
+        let in_flight = self.replicating.clone();
+        let cli = cli.clone();
+        let body = object.data.clone();
+        let content_type = object.content_type.clone().or_else(|| {
+            image::guess_format(&body)
+                .ok()
+                .map(|f| f.to_mime_type().to_string())
+        });
+        // Replication is best effort and must not delay the response.
+        tokio::spawn(async move {
+            if let Err(err) = cli
+                .put_object(bucket.clone(), key.clone(), body, content_type.as_deref())
+                .await
+            {
+                tracing::warn!("failed to replicate an original; {replica} {err:?}");
+            }
+            in_flight
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&replica);
+        });
+    }
+
+    // Refuses a fetch while the circuit breaker is open, and records how it went otherwise.
+    async fn with_breaker<T>(
+        &self,
+        source: &Source,
+        fetching: impl std::future::Future<Output = Result<T, Box<dyn std::error::Error>>>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let breaker = source.circuit_breaker.as_ref();
+        if breaker.is_some_and(|b| !b.try_acquire()) {
+            return Err(Box::from("circuit breaker is open"));
+        }
+        let result = fetching.await;
+        if result.is_err() {
+            let scheme = source.uri.scheme_str().unwrap_or_default();
+            self.metrics.count_origin_error(scheme);
+        }
+        if let Some(b) = breaker {
+            match &result {
+                Ok(_) => b.record_success(),
+                Err(_) => b.record_failure(),
+            }
+        }
+        result
+    }
+
+    // Relaying an original is possible only when nothing else needs a whole copy of it.
+    pub fn streamable(&self, host: Option<&str>, req_path: &str) -> bool {
+        self.originals.is_none()
+            && self.routes.at(host, req_path).is_some_and(|p| {
+                p.replicate_to.is_none()
+                    && p.sources
+                        .iter()
+                        .all(|v| matches!(v.uri.scheme_str(), Some("s3" | "http" | "https")))
+            })
+    }
+
+    pub async fn stream_image(
+        &self,
+        host: Option<&str>,
+        req_path: &str,
+    ) -> Result<Option<Fetched>, Box<dyn std::error::Error>> {
+        let Some(provider) = self.routes.at(host, req_path) else {
+            return Ok(None);
+        };
+        let mut last_err = None;
+        for (i, source) in provider.sources.iter().enumerate() {
+            let src = source.uri.to_string();
+            let opening = self.open(provider, &source.uri, req_path);
+            // Box<dyn Error> is not Send, so it must not be held across the next await.
+            match self
+                .with_breaker(source, opening)
+                .await
+                .map_err(|e| e.to_string())
+            {
+                Ok(Some(chunks)) => {
+                    log_source(req_path, i, &src);
+                    let location = provider.location(&source.uri, req_path);
+                    return peek(chunks, i, location).await.map(Some);
+                }
+                Ok(None) => last_err = None,
+                Err(err) => {
+                    tracing::warn!("failed to open a source; {req_path} {src} {err}");
+                    last_err = Some(format!("{src} {err}"));
                 }
             }
-            Err(_) => Ok(None),
         }
+        match last_err {
+            Some(err) => Err(Box::from(err)),
+            None => Ok(None),
+        }
+    }
+
+    async fn open(
+        &self,
+        provider: &Provider,
+        uri: &axum::http::uri::Uri,
+        req_path: &str,
+    ) -> Result<Option<infra::Chunks>, Box<dyn std::error::Error>> {
+        let (prefix, target_path) = provider.target(req_path)?;
+        let req_path = target_path.as_str();
+        match uri.scheme().map_or("", |v| v.as_str()) {
+            "s3" => {
+                let (bucket, key) = build_bucket_and_object_key(uri, prefix, req_path)?;
+                let span = tracing::info_span!("s3.get_object", bucket, key);
+                self.client
+                    .s3_client(provider.s3_client.as_deref())
+                    .ok_or("undefined s3 client")?
+                    .open_object(bucket, key)
+                    .instrument(span)
+                    .await
+            }
+            "http" | "https" => {
+                let url = build_url(uri, prefix, req_path)?;
+                let span = tracing::info_span!("web.get", url);
+                self.client.web.open(url).instrument(span).await
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn fetch(
+        &self,
+        provider: &Provider,
+        uri: &axum::http::uri::Uri,
+        req_path: &str,
+        cached: Option<&infra::Object>,
+    ) -> Result<Option<infra::Revalidated>, Box<dyn std::error::Error>> {
+        let (prefix, target_path) = provider.target(req_path)?;
+        let req_path = target_path.as_str();
+        // Origins other than S3 and HTTP give no validators, so they are downloaded again.
+        let data = match uri.scheme().map_or("", |v| v.as_str()) {
+            "s3" => {
+                let (bucket, key) = build_bucket_and_object_key(uri, prefix, req_path)?;
+                let span = tracing::info_span!("s3.get_object", bucket, key);
+                return self
+                    .client
+                    .s3_client(provider.s3_client.as_deref())
+                    .ok_or("undefined s3 client")?
+                    .revalidate_object(bucket, key, cached)
+                    .instrument(span)
+                    .await;
+            }
+            "gs" => {
+                let (bucket, name) = build_bucket_and_object_key(uri, prefix, req_path)?;
+                let span = tracing::info_span!("gcs.get_object", bucket, name);
+                self.client
+                    .gcs
+                    .as_ref()
+                    .ok_or("gcs client is not configured")?
+                    .get_object(bucket, name)
+                    .instrument(span)
+                    .await?
+            }
+            "az" | "azblob" => {
+                let (container, name) = build_bucket_and_object_key(uri, prefix, req_path)?;
+                let span = tracing::info_span!("azure.get_blob", container, name);
+                self.client
+                    .azure
+                    .as_ref()
+                    .ok_or("azure client is not configured")?
+                    .get_blob(container, name)
+                    .instrument(span)
+                    .await?
+            }
+            "http" | "https" => {
+                let url = build_url(uri, prefix, req_path)?;
+                let span = tracing::info_span!("web.get", url);
+                return self
+                    .client
+                    .web
+                    .revalidate(url, cached)
+                    .instrument(span)
+                    .await;
+            }
+            "file" => {
+                let local_path = build_local_path(uri, prefix, req_path)?;
+                let span = tracing::info_span!("file.read", path = ?local_path);
+                self.client.file.read(local_path).instrument(span).await?
+            }
+            _ => None,
+        };
+        Ok(data.map(|v| infra::Revalidated::Modified(infra::Object::from(v))))
     }
 
     pub fn process_image(
         &self,
-        original: &Vec<u8>,
+        original: &bytes::Bytes,
         params: &query::Query,
         content: content::Format,
-    ) -> Result<(&'static str, Vec<u8>), Box<dyn std::error::Error>> {
+        stamp: Option<&watermark::Stamp>,
+        caption: Option<&text::Caption>,
+    ) -> Result<(&'static str, bytes::Bytes), Box<dyn std::error::Error>> {
         // https://docs.rs/image/latest/image/struct.ImageReader.html
-        let cursor = std::io::Cursor::new(original);
+        let cursor = std::io::Cursor::new(original.as_ref());
         let reader = ImageReader::new(cursor).with_guessed_format()?;
-        let mut format = match reader.format() {
-            Some(f) => f,
-            None => return self.process_unknown_format(reader.into_inner().into_inner()),
-        };
-        if params.as_is() {
-            let orig = reader.into_inner().into_inner().to_owned();
-            return Ok((format.to_mime_type(), orig));
-        }
-        if format == ImageFormat::Gif {
-            return self.process_gif(reader.into_inner().into_inner(), params);
-        }
-        let mut decoder = reader.into_decoder()?;
-        let orientation = decoder.orientation().ok();
-        // https://docs.rs/image/latest/image/enum.DynamicImage.html
-        let mut img = if format == ImageFormat::Jpeg {
-            match self.convert_jpeg_color_if_needed(original) {
-                Some((width, height, converted)) => {
-                    image::RgbImage::from_raw(width, height, converted)
-                        .map_or(DynamicImage::from_decoder(decoder)?, |b| {
-                            DynamicImage::ImageRgb8(b)
-                        })
-                }
-                None => DynamicImage::from_decoder(decoder)?,
+        let as_is = params.as_is() && stamp.is_none();
+        let (mut format, mut img) = match reader.format() {
+            Some(f) if as_is => return Ok((f.to_mime_type(), original.clone())),
+            Some(ImageFormat::Gif) => {
+                let original = reader.into_inner().into_inner();
+                return self.process_gif(original, params, stamp, caption);
             }
-        } else {
-            DynamicImage::from_decoder(decoder)?
+            Some(f) => (f, self.decode(original, reader, f)?),
+            None if as_is => return self.process_unknown_format(reader.into_inner().into_inner()),
+            // SVG is rasterized into PNG unless another format is asked for.
+            None => (ImageFormat::Png, rasterize_svg(original, params)?),
         };
-        if let Some(o) = orientation {
-            img.apply_orientation(o);
-        }
         if params.grayscale() {
             img = img.grayscale();
         } else if params.inverse() {
             img.invert();
         }
         if let Some((width, height)) = params.dimensions() {
+            let _span = tracing::info_span!("resize", width, height).entered();
             // https://docs.rs/image/latest/image/struct.ImageBuffer.html
             if width != img.width() || height != img.height() {
                 if params.cropping() {
@@ -253,12 +1198,22 @@ impl State {
                 img = img.blur(sigma);
             }
         }
+        if let Some(stamp) = stamp {
+            let _span = tracing::info_span!("watermark").entered();
+            let fitted = stamp.fit(img.width(), img.height());
+            stamp.apply(&mut img, &fitted);
+        }
+        if let Some(caption) = caption {
+            let _span = tracing::info_span!("text").entered();
+            caption.apply(&mut img);
+        }
         let mut buffer = std::io::Cursor::new(Vec::new());
         if params.use_webp() && content.webp_accepted() {
             format = ImageFormat::WebP;
         } else if params.use_avif() && content.avif_accepted() {
             format = ImageFormat::Avif;
         }
+        let _span = tracing::info_span!("encode", format = format.to_mime_type()).entered();
         match format {
             // https://docs.rs/image/latest/image/codecs/index.html
             ImageFormat::Png => {
@@ -305,18 +1260,96 @@ impl State {
             }
             _ => img.write_to(&mut buffer, format)?,
         }
-        Ok((format.to_mime_type(), buffer.into_inner()))
+        Ok((format.to_mime_type(), buffer.into_inner().into()))
+    }
+
+    // Decodes a raster original upright, where CMYK JPEG is converted with a profile if any.
+    fn decode(
+        &self,
+        original: &bytes::Bytes,
+        reader: ImageReader<std::io::Cursor<&[u8]>>,
+        format: ImageFormat,
+    ) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+        let _span = tracing::info_span!("decode").entered();
+        let mut decoder = reader.into_decoder()?;
+        let orientation = decoder.orientation().ok();
+        // https://docs.rs/image/latest/image/enum.DynamicImage.html
+        let mut img = if format == ImageFormat::Jpeg {
+            match self.convert_jpeg_color_if_needed(original) {
+                Some((width, height, converted)) => {
+                    image::RgbImage::from_raw(width, height, converted)
+                        .map_or(DynamicImage::from_decoder(decoder)?, |b| {
+                            DynamicImage::ImageRgb8(b)
+                        })
+                }
+                None => DynamicImage::from_decoder(decoder)?,
+            }
+        } else {
+            DynamicImage::from_decoder(decoder)?
+        };
+        if let Some(o) = orientation {
+            img.apply_orientation(o);
+        }
+        Ok(img)
+    }
+
+    pub fn placeholder(
+        &self,
+        original: &bytes::Bytes,
+    ) -> Result<Placeholder, Box<dyn std::error::Error>> {
+        let cursor = std::io::Cursor::new(original.as_ref());
+        let reader = ImageReader::new(cursor).with_guessed_format()?;
+        let format = reader
+            .format()
+            .ok_or("placeholders are made only of raster images")?;
+        let img = self.decode(original, reader, format)?;
+        let _span = tracing::info_span!("placeholder").entered();
+        // BlurHash is as good with a thumbnail, which is small enough to be embedded too.
+        let thumbnail = img
+            .thumbnail(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE)
+            .into_rgba8();
+        // https://github.com/woltapp/blurhash#how-do-i-pick-the-number-of-x-and-y-components
+        let (x, y) = if img.width() >= img.height() {
+            (4, 3)
+        } else {
+            (3, 4)
+        };
+        let blurhash = blurhash::encode(
+            x,
+            y,
+            thumbnail.width(),
+            thumbnail.height(),
+            thumbnail.as_raw(),
+        )?;
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        thumbnail.write_to(&mut buffer, ImageFormat::Png)?;
+        // https://developer.mozilla.org/en-US/docs/Web/URI/Schemes/data
+        let data_uri = format!(
+            "data:image/png;base64,{}",
+            BASE64_STANDARD.encode(buffer.into_inner())
+        );
+        Ok(Placeholder {
+            width: img.width(),
+            height: img.height(),
+            blurhash,
+            color: average_color(&thumbnail),
+            data_uri,
+        })
     }
 
     fn process_gif(
         &self,
-        original: &Vec<u8>,
+        original: &[u8],
         params: &query::Query,
-    ) -> Result<(&'static str, Vec<u8>), Box<dyn std::error::Error>> {
+        stamp: Option<&watermark::Stamp>,
+        caption: Option<&text::Caption>,
+    ) -> Result<(&'static str, bytes::Bytes), Box<dyn std::error::Error>> {
         let reader = std::io::Cursor::new(original);
         // https://docs.rs/image/latest/image/codecs/gif/index.html
         let mut decoder = gif::GifDecoder::new(reader)?;
         decoder.set_limits(Limits::no_limits())?;
+        // Frames share their size, so a logo is fitted only once.
+        let mut fitted: Option<RgbaImage> = None;
         // https://docs.rs/image/latest/image/struct.Frames.html
         let frames: Vec<_> = decoder
             .into_frames()
@@ -352,7 +1385,16 @@ impl State {
                         img = DynamicImage::ImageRgba8(bg);
                     }
                 }
-                Frame::new(img.to_rgba8())
+                let mut frame = img.to_rgba8();
+                if let Some(stamp) = stamp {
+                    let logo =
+                        fitted.get_or_insert_with(|| stamp.fit(frame.width(), frame.height()));
+                    stamp.apply(&mut frame, logo);
+                }
+                if let Some(caption) = caption {
+                    caption.apply(&mut frame);
+                }
+                Frame::new(frame)
             })
             .collect();
         let mut buffer = std::io::Cursor::new(Vec::new());
@@ -360,9 +1402,9 @@ impl State {
             // https://github.com/image-rs/image/issues/1983
             let mut encoder = gif::GifEncoder::new_with_speed(&mut buffer, 10);
             encoder.set_repeat(gif::Repeat::Infinite)?;
-            encoder.encode_frames(frames.into_iter())?;
+            encoder.encode_frames(frames)?;
         }
-        Ok((ImageFormat::Gif.to_mime_type(), buffer.into_inner()))
+        Ok((ImageFormat::Gif.to_mime_type(), buffer.into_inner().into()))
     }
 
     const MIME_TYPE_SVG: &'static str = "image/svg+xml";
@@ -370,29 +1412,9 @@ impl State {
     fn process_unknown_format(
         &self,
         original: &[u8],
-    ) -> Result<(&'static str, Vec<u8>), Box<dyn std::error::Error>> {
-        let s = if original.len() > 2 && original[0] == 255 && original[1] == 254 {
-            let b = original
-                .chunks(std::mem::size_of::<u16>())
-                .map(|e| u16::from_le_bytes(e.try_into().map_or([0x20, 0x00], |v| v)))
-                .collect::<Vec<_>>();
-            String::from_utf16(&b).map_err(|_| "failed to treat as UTF-16LE")?
-        } else if original.len() > 2 && original[0] == 254 && original[1] == 255 {
-            let b = original
-                .chunks(std::mem::size_of::<u16>())
-                .map(|e| u16::from_be_bytes(e.try_into().map_or([0x00, 0x20], |v| v)))
-                .collect::<Vec<_>>();
-            String::from_utf16(&b).map_err(|_| "failed to treat as UTF-16BE")?
-        } else {
-            std::str::from_utf8(original)
-                .map_err(|_| "unknown format")?
-                .to_string()
-        };
-        // https://docs.rs/resvg/latest/resvg/
-        // https://docs.rs/usvg/latest/usvg/struct.Tree.html
-        let opt = usvg::Options::default();
-        usvg::Tree::from_str(s.as_str(), &opt).map_err(|_err| "failed to parse as SVG")?;
-        Ok((Self::MIME_TYPE_SVG, s.into_bytes()))
+    ) -> Result<(&'static str, bytes::Bytes), Box<dyn std::error::Error>> {
+        let (s, _) = parse_svg(original)?;
+        Ok((Self::MIME_TYPE_SVG, s.into_bytes().into()))
     }
 
     fn convert_jpeg_color_if_needed(&self, original: &[u8]) -> Option<(u32, u32, Vec<u8>)> {
@@ -403,14 +1425,9 @@ impl State {
         let mut decoder = zune_jpeg::JpegDecoder::new(original);
         decoder.decode_headers().ok()?;
         let (width, height) = decoder.dimensions()?;
-        let color_space = decoder.get_input_colorspace()?;
-        // https://docs.rs/zune-core/latest/zune_core/colorspace/enum.ColorSpace.html
+        let color_space = cmyk_color_space(&decoder)?;
         use zune_jpeg::zune_core::colorspace::ColorSpace;
-        let size = match color_space {
-            ColorSpace::YCCK => ColorSpace::YCCK.num_components(),
-            ColorSpace::CMYK => ColorSpace::CMYK.num_components(),
-            _ => return None,
-        };
+        let size = color_space.num_components();
         if size > 4 {
             return None;
         }
@@ -466,6 +1483,193 @@ impl State {
     }
 }
 
+// Tells the format from the first chunk, and buffers the rest when it is not a raster image
+// because an SVG or an unknown format has to be inspected as a whole.
+// Every hit is logged with its source, and at info when a fallback source served it.
+fn log_source(req_path: &str, index: usize, src: &str) {
+    if index > 0 {
+        tracing::info!("served by a fallback source; {req_path} {index} {src}");
+    } else {
+        tracing::debug!("served by the primary source; {req_path} {src}");
+    }
+}
+
+async fn peek(
+    mut chunks: infra::Chunks,
+    source: usize,
+    location: String,
+) -> Result<Fetched, Box<dyn std::error::Error>> {
+    use futures_util::StreamExt;
+    let head = match chunks.next().await {
+        Some(chunk) => chunk.map_err(|e| e as Box<dyn std::error::Error>)?,
+        None => bytes::Bytes::new(),
+    };
+    if let Ok(format) = image::guess_format(&head) {
+        let rest = futures_util::stream::once(async move { Ok(head) }).chain(chunks);
+        let body = axum::body::Body::from_stream(rest);
+        let mime_type = format.to_mime_type();
+        return Ok(Fetched::Passthrough {
+            mime_type,
+            body,
+            source,
+            location,
+        });
+    }
+    let mut data = bytes::BytesMut::from(head);
+    while let Some(chunk) = chunks.next().await {
+        data.extend_from_slice(&chunk.map_err(|e| e as Box<dyn std::error::Error>)?);
+    }
+    Ok(Fetched::Buffered(Original {
+        data: data.freeze(),
+        source,
+        location,
+        cache: None,
+    }))
+}
+
+impl Routes {
+    fn insert(&mut self, hosts: &[String], prefix: String, provider: Provider) {
+        self.all.push(provider.clone());
+        if hosts.is_empty() {
+            self.any
+                .insert(prefix, provider)
+                .expect("failed to make router with providers");
+            return;
+        }
+        for host in hosts.iter() {
+            let i = match self.hosts.iter().position(|(pattern, _)| pattern == host) {
+                Some(i) => i,
+                None => {
+                    self.hosts.push((host.clone(), matchit::Router::new()));
+                    self.hosts.len() - 1
+                }
+            };
+            self.hosts[i]
+                .1
+                .insert(prefix.clone(), provider.clone())
+                .expect("failed to make router with providers");
+        }
+    }
+
+    // Exact hosts come before wildcards, and longer wildcards before shorter ones.
+    fn sort(&mut self) {
+        self.hosts.sort_by_key(|(pattern, _)| {
+            (pattern.starts_with('*'), std::cmp::Reverse(pattern.len()))
+        });
+    }
+
+    fn at(&self, host: Option<&str>, req_path: &str) -> Option<&Provider> {
+        // https://docs.rs/matchit/latest/matchit/index.html
+        // https://docs.rs/matchit/latest/matchit/struct.Router.html
+        if let Some(host) = host.map(normalize_host) {
+            for (pattern, router) in self.hosts.iter() {
+                if !match_host(pattern, host.as_str()) {
+                    continue;
+                }
+                if let Ok(matched) = router.at(req_path) {
+                    return Some(matched.value);
+                }
+            }
+        }
+        self.any.at(req_path).ok().map(|v| v.value)
+    }
+
+    fn at_pattern(&self, pattern: &str, req_path: &str) -> Option<&Provider> {
+        self.hosts
+            .iter()
+            .filter(|(p, _)| p == pattern)
+            .find_map(|(_, router)| router.at(req_path).ok())
+            .or_else(|| self.any.at(req_path).ok())
+            .map(|v| v.value)
+    }
+}
+
+// https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Host
+fn normalize_host(host: &str) -> String {
+    let host = match host.strip_prefix('[') {
+        Some(v) => v.split(']').next().unwrap_or(v),
+        None => host.split(':').next().unwrap_or(host),
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// Gives the host of Origin, or of Referer without it, where an unreadable one has no host.
+// https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Origin
+fn referring_host(headers: &axum::http::HeaderMap) -> Option<Option<String>> {
+    let value = [axum::http::header::ORIGIN, axum::http::header::REFERER]
+        .iter()
+        .filter_map(|name| headers.get(name))
+        .find(|v| v.as_bytes() != b"null")?;
+    let host = value
+        .to_str()
+        .ok()
+        .and_then(|v| v.parse::<axum::http::Uri>().ok())
+        .and_then(|v| v.host().map(normalize_host));
+    Some(host)
+}
+
+fn match_host(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix('*') {
+        Some(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+        None => pattern == host,
+    }
+}
+
+impl Provider {
+    // Gives a prefix and a path to build an origin location with, rewritten by the first matching rule.
+    fn target(&self, req_path: &str) -> Result<(&str, String), Box<dyn std::error::Error>> {
+        if self.rewrites.is_empty() {
+            return Ok((self.path.as_str(), req_path.to_string()));
+        }
+        let relative = clean_path(req_path, self.path.as_str())?;
+        for r in self.rewrites.iter() {
+            // https://docs.rs/regex/latest/regex/struct.Captures.html#method.expand
+            if let Some(caps) = r.pattern.captures(relative.as_str()) {
+                let mut dst = String::new();
+                caps.expand(r.replacement.as_str(), &mut dst);
+                return Ok(("", dst));
+            }
+        }
+        Ok((self.path.as_str(), req_path.to_string()))
+    }
+
+    // Names where an original is for logs, such as an object key with its bucket or a URL.
+    fn location(&self, uri: &axum::http::uri::Uri, req_path: &str) -> String {
+        let Ok((prefix, target_path)) = self.target(req_path) else {
+            return uri.to_string();
+        };
+        let req_path = target_path.as_str();
+        let location = match uri.scheme_str() {
+            Some(scheme @ ("s3" | "gs" | "az" | "azblob")) => {
+                build_bucket_and_object_key(uri, prefix, req_path)
+                    .map(|(bucket, key)| format!("{scheme}://{bucket}/{key}"))
+            }
+            Some("http" | "https") => build_url(uri, prefix, req_path),
+            Some("file") => build_local_path(uri, prefix, req_path),
+            _ => Ok(uri.to_string()),
+        };
+        location.unwrap_or_else(|_| uri.to_string())
+    }
+
+    // Providers bound to hosts may share a path, so the first host tells them apart.
+    fn cache_key(&self, req_path: &str) -> String {
+        let host = self.hosts.first().map_or("", |v| v.as_str());
+        format!("{host}{req_path}")
+    }
+
+    fn default_query(&self, req_path: &str) -> Option<String> {
+        let relative = clean_path(req_path, self.path.as_str()).ok()?;
+        for r in self.rewrites.iter() {
+            if let Some(caps) = r.pattern.captures(relative.as_str()) {
+                let mut dst = String::new();
+                caps.expand(r.query.as_ref()?.as_str(), &mut dst);
+                return Some(dst);
+            }
+        }
+        None
+    }
+}
+
 impl CMYK2RGB {
     pub fn with_icc_profile(d: &[u8]) -> Option<Self> {
         // https://github.com/kornelski/rust-lcms2/blob/main/examples/thread.rs
@@ -492,12 +1696,164 @@ impl CMYK2RGB {
     }
 }
 
+// Reads an original of no raster format as SVG text, which may be UTF-16 with a BOM.
+fn parse_svg(original: &[u8]) -> Result<(String, usvg::Tree), Box<dyn std::error::Error>> {
+    let s = if original.len() > 2 && original[0] == 255 && original[1] == 254 {
+        let b = original
+            .chunks(std::mem::size_of::<u16>())
+            .map(|e| u16::from_le_bytes(e.try_into().map_or([0x20, 0x00], |v| v)))
+            .collect::<Vec<_>>();
+        String::from_utf16(&b).map_err(|_| "failed to treat as UTF-16LE")?
+    } else if original.len() > 2 && original[0] == 254 && original[1] == 255 {
+        let b = original
+            .chunks(std::mem::size_of::<u16>())
+            .map(|e| u16::from_be_bytes(e.try_into().map_or([0x00, 0x20], |v| v)))
+            .collect::<Vec<_>>();
+        String::from_utf16(&b).map_err(|_| "failed to treat as UTF-16BE")?
+    } else {
+        std::str::from_utf8(original)
+            .map_err(|_| "unknown format")?
+            .to_string()
+    };
+    // https://docs.rs/resvg/latest/resvg/
+    // https://docs.rs/usvg/latest/usvg/struct.Tree.html
+    let opt = usvg::Options::default();
+    let tree = usvg::Tree::from_str(s.as_str(), &opt).map_err(|_err| "failed to parse as SVG")?;
+    Ok((s, tree))
+}
+
+// Renders at the scale which fits or fills the requested dimensions, so that resizing afterwards
+// only crops or pads it.
+fn rasterize_svg(
+    original: &[u8],
+    params: &query::Query,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let _span = tracing::info_span!("rasterize").entered();
+    let (_, tree) = parse_svg(original)?;
+    let size = tree.size();
+    let (w, h) = (size.width(), size.height());
+    let scale = match params.dimensions() {
+        Some((width, height)) if params.cropping() => (width as f32 / w).max(height as f32 / h),
+        Some((width, height)) => (width as f32 / w).min(height as f32 / h),
+        None => 1.0,
+    }
+    .min(MAX_RASTER_SIZE as f32 / w.max(h));
+    let width = ((w * scale).round() as u32).max(1);
+    let height = ((h * scale).round() as u32).max(1);
+    // https://docs.rs/resvg/latest/resvg/fn.render.html
+    let mut pixmap =
+        resvg::tiny_skia::Pixmap::new(width, height).ok_or("failed to allocate a pixmap")?;
+    let transform = resvg::tiny_skia::Transform::from_scale(width as f32 / w, height as f32 / h);
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+    // Pixmaps are premultiplied by alpha.
+    let data = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect();
+    let img = RgbaImage::from_raw(width, height, data).ok_or("failed to rasterize SVG")?;
+    Ok(DynamicImage::ImageRgba8(img))
+}
+
+// Averages colors weighted by alpha, so that transparent pixels do not darken it.
+fn average_color(img: &RgbaImage) -> String {
+    let mut sum = [0u64; 3];
+    let mut weight = 0u64;
+    for Rgba([r, g, b, a]) in img.pixels() {
+        let a = *a as u64;
+        sum[0] += *r as u64 * a;
+        sum[1] += *g as u64 * a;
+        sum[2] += *b as u64 * a;
+        weight += a;
+    }
+    let [r, g, b] = sum.map(|v| v.checked_div(weight).unwrap_or_default());
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+// https://docs.rs/zune-core/latest/zune_core/colorspace/enum.ColorSpace.html
+fn cmyk_color_space(
+    decoder: &zune_jpeg::JpegDecoder<&[u8]>,
+) -> Option<zune_jpeg::zune_core::colorspace::ColorSpace> {
+    use zune_jpeg::zune_core::colorspace::ColorSpace;
+    match decoder.get_input_colorspace()? {
+        v @ (ColorSpace::YCCK | ColorSpace::CMYK) => Some(v),
+        _ => None,
+    }
+}
+
+fn inspect_image(original: &[u8]) -> Result<Info, Box<dyn std::error::Error>> {
+    let cursor = std::io::Cursor::new(original);
+    let reader = ImageReader::new(cursor).with_guessed_format()?;
+    let Some(format) = reader.format() else {
+        let (_, tree) = parse_svg(original)?;
+        let size = tree.size().to_int_size();
+        return Ok(Info {
+            format: State::MIME_TYPE_SVG,
+            width: size.width(),
+            height: size.height(),
+            color_type: None,
+            bit_depth: None,
+            frames: None,
+            icc_profile: false,
+            cmyk: None,
+            bytes: original.len(),
+        });
+    };
+    let mut decoder = reader.into_decoder()?;
+    let (mut width, mut height) = decoder.dimensions();
+    // https://docs.rs/image/latest/image/metadata/enum.Orientation.html
+    use image::metadata::Orientation;
+    if let Ok(
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH,
+    ) = decoder.orientation()
+    {
+        std::mem::swap(&mut width, &mut height);
+    }
+    let color_type = decoder.color_type();
+    let icc_profile = decoder.icc_profile().ok().flatten().is_some();
+    let frames = if format == ImageFormat::Gif {
+        let mut decoder = gif::GifDecoder::new(std::io::Cursor::new(original))?;
+        decoder.set_limits(Limits::no_limits())?;
+        Some(decoder.into_frames().count())
+    } else {
+        None
+    };
+    let cmyk = if format == ImageFormat::Jpeg {
+        use zune_jpeg::zune_core::colorspace::ColorSpace;
+        let mut decoder = zune_jpeg::JpegDecoder::new(original);
+        decoder.decode_headers()?;
+        cmyk_color_space(&decoder).map(|v| match v {
+            ColorSpace::YCCK => "YCCK",
+            _ => "CMYK",
+        })
+    } else {
+        None
+    };
+    Ok(Info {
+        format: format.to_mime_type(),
+        width,
+        height,
+        color_type: Some(format!("{color_type:?}")),
+        bit_depth: Some(color_type.bits_per_pixel() / color_type.channel_count() as u16),
+        frames,
+        icc_profile,
+        cmyk,
+        bytes: original.len(),
+    })
+}
+
 fn build_bucket_and_object_key(
     src_uri: &axum::http::uri::Uri,
     req_prefix: &str,
     req_path: &str,
 ) -> Result<(String, String), Box<dyn std::error::Error>> {
-    let bucket = src_uri.host().ok_or("s3 client src is wrong")?;
+    let bucket = src_uri.host().ok_or("bucket of a src is missing")?;
     let path_1 = src_uri.path();
     let path_2 = clean_path(req_path, req_prefix)?;
     if let Some(key_path) = std::path::Path::new(path_1).join(path_2).as_path().to_str() {
@@ -834,4 +2190,535 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_inspect_image() {
+        let raster = |format, color_type: &str, frames| Info {
+            format,
+            width: 512,
+            height: 512,
+            color_type: Some(color_type.to_string()),
+            bit_depth: Some(8),
+            frames,
+            icc_profile: false,
+            cmyk: None,
+            bytes: 0,
+        };
+        let cases = [
+            ("lenna.jpg", Some(raster("image/jpeg", "Rgb8", None))),
+            ("lenna.png", Some(raster("image/png", "Rgb8", None))),
+            ("lenna.gif", Some(raster("image/gif", "Rgba8", Some(1)))),
+            (
+                "logo.svg",
+                Some(Info {
+                    format: "image/svg+xml",
+                    width: 46,
+                    height: 46,
+                    color_type: None,
+                    bit_depth: None,
+                    frames: None,
+                    icc_profile: false,
+                    cmyk: None,
+                    bytes: 0,
+                }),
+            ),
+            ("lenna.txt", None),
+        ];
+        for (name, want) in cases {
+            let data = std::fs::read(format!("images/{name}")).unwrap();
+            let got = inspect_image(&data).ok();
+            let want = want.map(|v| Info {
+                bytes: data.len(),
+                ..v
+            });
+            assert_eq!(got, want, "case: {name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_placeholder() {
+        let client = infra::Client::for_test().await;
+        let state = State::new(Vec::new(), client);
+        for name in ["lenna.jpg", "lenna.png", "lenna.gif"] {
+            let data = bytes::Bytes::from(std::fs::read(format!("images/{name}")).unwrap());
+            let got = state
+                .placeholder(&data)
+                .expect("failed to make a placeholder");
+            assert_eq!((got.width, got.height), (512, 512), "case: {name}");
+            // https://github.com/woltapp/blurhash/blob/master/Algorithm.md
+            assert_eq!(got.blurhash.len(), 4 + 2 * 4 * 3, "case: {name}");
+            assert_eq!(got.color.len(), 7, "case: {name}");
+            let preview = got
+                .data_uri
+                .strip_prefix("data:image/png;base64,")
+                .expect("not a data URI of PNG");
+            let preview = BASE64_STANDARD.decode(preview).unwrap();
+            assert_eq!(dimensions(&preview), Some((16, 16)), "case: {name}");
+        }
+        let svg = bytes::Bytes::from(std::fs::read("images/logo.svg").unwrap());
+        assert!(state.placeholder(&svg).is_err());
+    }
+
+    #[test]
+    fn test_average_color() {
+        let mut img = RgbaImage::from_pixel(2, 1, Rgba([255, 0, 0, 255]));
+        img.put_pixel(1, 0, Rgba([0, 0, 255, 0]));
+        assert_eq!(average_color(&img), "#ff0000");
+        img.put_pixel(1, 0, Rgba([0, 0, 255, 255]));
+        assert_eq!(average_color(&img), "#7f007f");
+        assert_eq!(average_color(&RgbaImage::new(1, 1)), "#000000");
+    }
+
+    #[test]
+    fn test_rasterize_svg() {
+        let original = std::fs::read("images/logo.svg").unwrap();
+        for (query, want) in [
+            ("", (46, 46)),
+            ("w=300&h=200", (200, 200)),
+            ("w=300&h=200&crop=true", (300, 300)),
+            ("w=10000&h=8000", (MAX_RASTER_SIZE, MAX_RASTER_SIZE)),
+        ] {
+            let params = serde_urlencoded::from_str::<query::Query>(query).unwrap();
+            let img = rasterize_svg(&original, &params).unwrap();
+            assert_eq!((img.width(), img.height()), want, "case: {query}");
+            assert_eq!(
+                img.as_rgba8().unwrap().get_pixel(0, 0)[3],
+                0,
+                "case: {query}"
+            );
+        }
+        let params = serde_urlencoded::from_str::<query::Query>("").unwrap();
+        assert!(rasterize_svg(b"<svg", &params).is_err());
+    }
+
+    #[test]
+    fn test_routes() {
+        let provider = |path: &str, hosts: &[&str]| Provider {
+            path: path.to_string(),
+            hosts: hosts.iter().map(|v| v.to_string()).collect(),
+            sources: Vec::new(),
+            fallback_path: "".to_string(),
+            success_even_no_content: false,
+            s3_client: None,
+            replicate_to: None,
+            rewrites: Vec::new(),
+            rate_limiter: None,
+            hotlink: None,
+            cors: None,
+            watermark: None,
+        };
+        let mut routes = Routes::default();
+        for p in [
+            provider("any", &[]),
+            provider("wide", &["*.example.com"]),
+            provider("narrow", &["*.cdn.example.com"]),
+            provider("exact", &["img.cdn.example.com", "::1"]),
+            provider("all", &["*"]),
+        ] {
+            let hosts = p.hosts.clone();
+            routes.insert(&hosts, "/images/{*p}".to_string(), p);
+        }
+        routes.sort();
+        for (host, req_path, want) in [
+            (None, "/images/a.jpg", Some("any")),
+            (
+                Some("IMG.cdn.example.com:3000"),
+                "/images/a.jpg",
+                Some("exact"),
+            ),
+            (Some("img.cdn.example.com."), "/images/a.jpg", Some("exact")),
+            (Some("[::1]:3000"), "/images/a.jpg", Some("exact")),
+            (Some("foo.cdn.example.com"), "/images/a.jpg", Some("narrow")),
+            (Some("cdn.example.com"), "/images/a.jpg", Some("wide")),
+            (Some("example.com"), "/images/a.jpg", Some("all")),
+            (Some("example.com"), "/other/a.jpg", None),
+        ] {
+            let got = routes.at(host, req_path).map(|v| v.path.as_str());
+            assert_eq!(got, want, "case: {host:?} {req_path}");
+        }
+        assert_eq!(
+            routes
+                .at_pattern("*.example.com", "/images/a.jpg")
+                .map(|v| v.path.as_str()),
+            Some("wide")
+        );
+        assert_eq!(
+            routes
+                .at_pattern("", "/images/a.jpg")
+                .map(|v| v.path.as_str()),
+            Some("any")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_referral() {
+        let client = infra::Client::for_test().await;
+        let hotlink = |allow_empty_referer, use_fallback| {
+            Some(config::hotlink::Config {
+                allowed_referers: Vec::from([
+                    "Example.com".to_string(),
+                    "*.example.com".to_string(),
+                ]),
+                allow_empty_referer: Some(allow_empty_referer),
+                use_fallback: Some(use_fallback),
+            })
+        };
+        let providers = Vec::from([
+            config::Provider {
+                path: "strict".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                hotlink: hotlink(false, false),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "lenient".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                hotlink: hotlink(true, true),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "open".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                ..Default::default()
+            },
+        ]);
+        let state = State::new(providers, client);
+        for (req_path, origin, referer, want) in [
+            ("/strict/a.jpg", None, None, Referral::Forbidden),
+            (
+                "/strict/a.jpg",
+                None,
+                Some("https://example.com/items/1"),
+                Referral::Allowed,
+            ),
+            (
+                "/strict/a.jpg",
+                None,
+                Some("https://www.example.com:8443/"),
+                Referral::Allowed,
+            ),
+            (
+                "/strict/a.jpg",
+                None,
+                Some("https://example.com.evil.test/"),
+                Referral::Forbidden,
+            ),
+            (
+                "/strict/a.jpg",
+                Some("https://editor.example.com"),
+                Some("https://evil.test/"),
+                Referral::Allowed,
+            ),
+            (
+                "/strict/a.jpg",
+                Some("null"),
+                Some("https://evil.test/"),
+                Referral::Forbidden,
+            ),
+            (
+                "/strict/a.jpg",
+                None,
+                Some("not a url"),
+                Referral::Forbidden,
+            ),
+            ("/lenient/a.jpg", None, None, Referral::Allowed),
+            ("/lenient/a.jpg", Some("null"), None, Referral::Allowed),
+            (
+                "/lenient/a.jpg",
+                None,
+                Some("https://evil.test/"),
+                Referral::Placeholder,
+            ),
+            (
+                "/open/a.jpg",
+                None,
+                Some("https://evil.test/"),
+                Referral::Allowed,
+            ),
+        ] {
+            let mut headers = axum::http::HeaderMap::new();
+            for (name, value) in [
+                (axum::http::header::ORIGIN, origin),
+                (axum::http::header::REFERER, referer),
+            ] {
+                if let Some(v) = value {
+                    headers.insert(name, axum::http::HeaderValue::from_static(v));
+                }
+            }
+            let got = state.check_referral(None, req_path, &headers);
+            assert_eq!(got, want, "case: {req_path} {origin:?} {referer:?}");
+        }
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let provider = Provider {
+            path: "img".to_string(),
+            hosts: Vec::new(),
+            sources: Vec::new(),
+            fallback_path: "".to_string(),
+            success_even_no_content: false,
+            s3_client: None,
+            replicate_to: None,
+            rewrites: Vec::from([
+                Rewrite {
+                    pattern: regex::Regex::new(
+                        r"^(?P<id>(?P<shard>[0-9a-z]{2})[0-9a-z]*)/thumb\.jpg$",
+                    )
+                    .unwrap(),
+                    replacement: "originals/$shard/$id.jpg".to_string(),
+                    query: Some("w=150&h=150".to_string()),
+                },
+                Rewrite {
+                    pattern: regex::Regex::new(
+                        r"^(?P<id>(?P<shard>[0-9a-z]{2})[0-9a-z]*)/(?P<size>\w+)\.jpg$",
+                    )
+                    .unwrap(),
+                    replacement: "originals/$shard/$id.jpg".to_string(),
+                    query: None,
+                },
+            ]),
+            rate_limiter: None,
+            hotlink: None,
+            cors: None,
+            watermark: None,
+        };
+        let uri = "s3://local-test/images"
+            .parse::<axum::http::uri::Uri>()
+            .unwrap();
+        for (req_path, want_key, want_query) in [
+            (
+                "/img/abc123/thumb.jpg",
+                "images/originals/ab/abc123.jpg",
+                Some("w=150&h=150"),
+            ),
+            (
+                "/img/abc123/large.jpg",
+                "images/originals/ab/abc123.jpg",
+                None,
+            ),
+            ("/img/ABC/large.png", "images/ABC/large.png", None),
+        ] {
+            let (prefix, path) = provider.target(req_path).unwrap();
+            let (_, got) = build_bucket_and_object_key(&uri, prefix, &path).unwrap();
+            assert_eq!(got, want_key, "case: {req_path}");
+            assert_eq!(
+                provider.default_query(req_path).as_deref(),
+                want_query,
+                "case: {req_path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_through_replication() {
+        let client = infra::Client::for_test().await;
+        let mut bucket_manager = infra::s3::BucketManager::new(client.s3.clone());
+        let bucket = bucket_manager
+            .create()
+            .await
+            .expect("failed to create a bucket");
+        let (port, mock_server) = infra::web::run_mock_server("/images", "images").await;
+        let providers = Vec::from([config::Provider {
+            path: "foo".to_string(),
+            src: config::Src::Many(Vec::from([
+                format!("s3://{bucket}/replica"),
+                format!("http://127.0.0.1:{port}/images"),
+            ])),
+            replicate_to: Some(format!("s3://{bucket}/replica")),
+            fallback_path: Some("/foo/lenna.jpg".to_string()),
+            ..Default::default()
+        }]);
+        let mut state = State::new(providers.clone(), client.clone());
+        state
+            .with_fallback(&None, &providers)
+            .await
+            .expect("failed to load fallback images");
+        assert!(state.replicating.lock().unwrap().is_empty());
+
+        // Tasks are not spawned until the test yields, so the second one is still deduped.
+        let provider = state.routes.at(None, "/foo/lenna.gif").unwrap();
+        let object = infra::Object::from(bytes::Bytes::from_static(b"GIF89a"));
+        state.replicate(provider, "/foo/lenna.gif", &object);
+        state.replicate(provider, "/foo/lenna.gif", &object);
+        assert_eq!(
+            Vec::from_iter(state.replicating.lock().unwrap().iter().cloned()),
+            Vec::from([format!("s3://{bucket}/replica/lenna.gif")])
+        );
+
+        let got = state
+            .get_image(None, "/foo/lenna.png")
+            .await
+            .expect("failed to get an image")
+            .expect("image not found");
+        assert_eq!(got.source, 1);
+
+        let mut replica = None;
+        for _ in 0..50 {
+            replica = client
+                .s3
+                .get_object(bucket.clone(), "replica/lenna.png".to_string())
+                .await
+                .expect("failed to get a replica");
+            if replica.is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(replica.map(|v| v.data), Some(got.data));
+
+        let got = state
+            .get_image(None, "/foo/lenna.png")
+            .await
+            .expect("failed to get an image")
+            .expect("image not found");
+        assert_eq!(got.source, 0);
+        let fallback = client
+            .s3
+            .get_object(bucket.clone(), "replica/lenna.jpg".to_string())
+            .await
+            .expect("failed to get a replica");
+        assert!(fallback.is_none());
+
+        bucket_manager.clean().await.unwrap();
+        mock_server.abort();
+    }
+    #[tokio::test]
+    async fn test_originals_cache() {
+        let client = infra::Client::for_test().await;
+        let (port, mock_server) = infra::web::run_mock_server("/images", "images").await;
+        let providers = Vec::from([config::Provider {
+            path: "foo".to_string(),
+            src: format!("http://127.0.0.1:{port}/images").into(),
+            ..Default::default()
+        }]);
+        let mut state = State::new(providers, client);
+        let want = std::fs::read("images/lenna.png").unwrap();
+        let cached = |state: &State, data: &[u8], last_modified: Option<String>| {
+            let cache = state.originals.as_ref().unwrap();
+            let entry = cache.get("/foo/lenna.png").expect("entry not found");
+            let object = infra::Object {
+                data: bytes::Bytes::copy_from_slice(data),
+                last_modified: last_modified.or(entry.object.last_modified),
+                ..entry.object
+            };
+            cache.insert("/foo/lenna.png".to_string(), entry.source, object);
+        };
+
+        // Every entry is stale and revalidated with the origin.
+        state.enable_originals_cache(config::cache::Config {
+            ttl_seconds: 0,
+            max_entries: 10,
+            max_bytes: None,
+        });
+        let got = state
+            .get_image(None, "/foo/lenna.png")
+            .await
+            .expect("failed to get an image")
+            .expect("image not found");
+        assert_eq!(got.data, want);
+        assert_eq!(got.cache, Some(CacheStatus::Miss));
+
+        cached(&state, b"cached", None);
+        let got = state
+            .get_image(None, "/foo/lenna.png")
+            .await
+            .expect("failed to get an image")
+            .expect("image not found");
+        assert_eq!(got.data, b"cached".to_vec());
+        assert_eq!(got.cache, Some(CacheStatus::Revalidated));
+
+        let outdated = Some("Sun, 06 Nov 1994 08:49:37 GMT".to_string());
+        cached(&state, b"cached", outdated);
+        let got = state
+            .get_image(None, "/foo/lenna.png")
+            .await
+            .expect("failed to get an image")
+            .expect("image not found");
+        assert_eq!(got.data, want);
+        assert_eq!(got.cache, Some(CacheStatus::Miss));
+
+        // A fresh entry is served without asking the origin.
+        state.enable_originals_cache(config::cache::Config {
+            ttl_seconds: 60,
+            max_entries: 10,
+            max_bytes: None,
+        });
+        let got = state
+            .get_image(None, "/foo/lenna.png")
+            .await
+            .expect("failed to get an image")
+            .expect("image not found");
+        assert_eq!(got.data, want);
+        assert_eq!(got.cache, Some(CacheStatus::Miss));
+
+        let outdated = Some("Sun, 06 Nov 1994 08:49:37 GMT".to_string());
+        cached(&state, b"cached", outdated);
+        let got = state
+            .get_image(None, "/foo/lenna.png")
+            .await
+            .expect("failed to get an image")
+            .expect("image not found");
+        assert_eq!(got.data, b"cached".to_vec());
+        assert_eq!(got.cache, Some(CacheStatus::Hit));
+
+        mock_server.abort();
+    }
+    #[tokio::test]
+    async fn test_stream_image() {
+        let client = infra::Client::for_test().await;
+        let (port, mock_server) = infra::web::run_mock_server("/images", "images").await;
+        let providers = Vec::from([
+            config::Provider {
+                path: "foo".to_string(),
+                src: config::Src::Many(Vec::from([
+                    format!("http://127.0.0.1:{port}/nothing"),
+                    format!("http://127.0.0.1:{port}/images"),
+                ])),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "bar".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                ..Default::default()
+            },
+        ]);
+        let state = State::new(providers, client);
+        assert!(state.streamable(None, "/foo/lenna.png"));
+        assert!(!state.streamable(None, "/bar/lenna.png"));
+        assert!(!state.streamable(None, "/baz/lenna.png"));
+
+        match state.stream_image(None, "/foo/lenna.png").await {
+            Ok(Some(Fetched::Passthrough {
+                mime_type,
+                body,
+                source,
+                location,
+            })) => {
+                assert_eq!(mime_type, "image/png");
+                assert_eq!(source, 1);
+                assert_eq!(
+                    location,
+                    format!("http://127.0.0.1:{port}/images/lenna.png")
+                );
+                let got = axum::body::to_bytes(body, usize::MAX)
+                    .await
+                    .expect("failed to read a body");
+                assert_eq!(got, std::fs::read("images/lenna.png").unwrap());
+            }
+            got => panic!("unexpected result: {got:?}"),
+        }
+        match state.stream_image(None, "/foo/logo.svg").await {
+            Ok(Some(Fetched::Buffered(got))) => {
+                assert_eq!(got.data, std::fs::read("images/logo.svg").unwrap());
+            }
+            got => panic!("unexpected result: {got:?}"),
+        }
+        assert!(matches!(
+            state.stream_image(None, "/foo/who.png").await,
+            Ok(None)
+        ));
+
+        mock_server.abort();
+    }
 }
@@ -0,0 +1,172 @@
+use super::config::health;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+const DEFAULT_PROBE_TTL: Duration = Duration::from_secs(10);
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl From<Result<(), String>> for Check {
+    fn from(result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => Self {
+                ok: true,
+                message: None,
+            },
+            Err(message) => Self {
+                ok: false,
+                message: Some(message),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SourceCheck {
+    pub index: usize,
+    #[serde(flatten)]
+    pub check: Check,
+}
+
+// A provider is reachable while any of its sources is.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProviderCheck {
+    pub path: String,
+    pub hosts: Vec<String>,
+    pub ok: bool,
+    pub sources: Vec<SourceCheck>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    pub ready: bool,
+    pub fallback: Check,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmyk_profile: Option<Check>,
+    pub providers: Vec<ProviderCheck>,
+    pub checked_at: String,
+}
+
+#[derive(Debug)]
+pub struct Health {
+    fallback: Check,
+    cmyk_profile: Option<Check>,
+    probe_ttl: Duration,
+    probe_timeout: Duration,
+    // Also makes concurrent readiness checks wait for a single round of probes.
+    probes: tokio::sync::Mutex<Option<(Instant, std::time::SystemTime, Vec<ProviderCheck>)>>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            fallback: Check::from(Ok(())),
+            cmyk_profile: None,
+            probe_ttl: DEFAULT_PROBE_TTL,
+            probe_timeout: DEFAULT_PROBE_TIMEOUT,
+            probes: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn configure(&mut self, cfg: health::Config) {
+        self.probe_ttl = Duration::from_secs(cfg.probe_ttl_seconds);
+        self.probe_timeout = Duration::from_millis(cfg.probe_timeout_ms);
+    }
+
+    pub fn set_fallback(&mut self, result: Result<(), String>) {
+        self.fallback = Check::from(result);
+    }
+
+    pub fn set_cmyk_profile(&mut self, result: Result<(), String>) {
+        self.cmyk_profile = Some(Check::from(result));
+    }
+
+    pub fn probe_timeout(&self) -> Duration {
+        self.probe_timeout
+    }
+
+    pub async fn report<F, Fut>(&self, probe: F) -> Report
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Vec<ProviderCheck>>,
+    {
+        let mut probes = self.probes.lock().await;
+        let fresh = probes
+            .as_ref()
+            .is_some_and(|(at, _, _)| at.elapsed() < self.probe_ttl);
+        if !fresh {
+            let providers = probe().await;
+            *probes = Some((Instant::now(), std::time::SystemTime::now(), providers));
+        }
+        let (_, checked_at, providers) = probes
+            .clone()
+            .unwrap_or_else(|| (Instant::now(), std::time::SystemTime::now(), Vec::new()));
+        // Origins are only reported, since an origin shared by all instances would take every one out.
+        let ready = self.fallback.ok && self.cmyk_profile.as_ref().is_none_or(|v| v.ok);
+        Report {
+            ready,
+            fallback: self.fallback.clone(),
+            cmyk_profile: self.cmyk_profile.clone(),
+            providers,
+            checked_at: httpdate::fmt_http_date(checked_at),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_report() {
+        let mut health = Health::new();
+        health.configure(health::Config {
+            probe_ttl_seconds: 60,
+            probe_timeout_ms: 100,
+        });
+        let provider = |ok| ProviderCheck {
+            path: "foo".to_string(),
+            hosts: Vec::new(),
+            ok,
+            sources: Vec::new(),
+        };
+        let got = health
+            .report(|| async { Vec::from([provider(false)]) })
+            .await;
+        assert!(got.ready);
+        assert!(!got.providers[0].ok);
+        assert_eq!(got.cmyk_profile, None);
+
+        // Probes are cached within the ttl.
+        let got = health
+            .report(|| async { Vec::from([provider(true)]) })
+            .await;
+        assert!(!got.providers[0].ok);
+
+        health.set_cmyk_profile(Err("failed to load an icc profile".to_string()));
+        let got = health.report(|| async { Vec::new() }).await;
+        assert!(!got.ready);
+        health.set_cmyk_profile(Ok(()));
+        health.set_fallback(Err("fallback images not found; /foo/no_img.jpg".to_string()));
+        let got = health.report(|| async { Vec::new() }).await;
+        assert!(!got.ready);
+        let json = serde_json::to_value(&got).unwrap();
+        assert_eq!(
+            json["fallback"],
+            serde_json::json!({"ok": false, "message": "fallback images not found; /foo/no_img.jpg"})
+        );
+        assert_eq!(json["cmyk_profile"], serde_json::json!({"ok": true}));
+    }
+}
@@ -0,0 +1,189 @@
+use super::super::config::cache;
+use super::Object;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Keeps originals in memory; stale entries are kept to be revalidated with the origin.
+#[derive(Debug)]
+pub struct Cache {
+    ttl: Duration,
+    max_bytes: usize,
+    entries: Mutex<Entries>,
+}
+
+// https://docs.rs/lru/latest/lru/struct.LruCache.html
+#[derive(Debug)]
+struct Entries {
+    lru: lru::LruCache<String, Entry>,
+    bytes: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub object: Object,
+    pub source: usize,
+    stored_at: Instant,
+}
+
+impl Cache {
+    pub fn new(cfg: cache::Config) -> Self {
+        let max_entries =
+            std::num::NonZeroUsize::new(cfg.max_entries).unwrap_or(std::num::NonZeroUsize::MIN);
+        Self {
+            ttl: Duration::from_secs(cfg.ttl_seconds),
+            max_bytes: cfg.max_bytes.unwrap_or(usize::MAX),
+            entries: Mutex::new(Entries {
+                lru: lru::LruCache::new(max_entries),
+                bytes: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Entry> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.lru.get(key).cloned()
+    }
+
+    pub fn is_fresh(&self, entry: &Entry) -> bool {
+        entry.stored_at.elapsed() < self.ttl
+    }
+
+    // The least recently used entries are evicted until both the count and the bytes fit.
+    pub fn insert(&self, key: String, source: usize, object: Object) {
+        let size = object.data.len();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.remove(&key);
+        if size > self.max_bytes {
+            return;
+        }
+        while entries.bytes + size > self.max_bytes {
+            let Some((_, v)) = entries.lru.pop_lru() else {
+                break;
+            };
+            entries.bytes -= v.object.data.len();
+        }
+        let stored_at = Instant::now();
+        let entry = Entry {
+            object,
+            source,
+            stored_at,
+        };
+        if let Some((_, v)) = entries.lru.push(key, entry) {
+            entries.bytes -= v.object.data.len();
+        }
+        entries.bytes += size;
+    }
+
+    pub fn touch(&self, key: &str) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = entries.lru.get_mut(key) {
+            entry.stored_at = Instant::now();
+        }
+    }
+
+    pub fn remove(&self, key: &str) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.remove(key);
+    }
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) {
+        if let Some(v) = self.lru.pop(key) {
+            self.bytes -= v.object.data.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache() {
+        let cache = Cache::new(cache::Config {
+            ttl_seconds: 60,
+            max_entries: 2,
+            max_bytes: None,
+        });
+        assert!(cache.get("a").is_none());
+
+        cache.insert(
+            "a".to_string(),
+            0,
+            Object::from(bytes::Bytes::from_static(b"a")),
+        );
+        cache.insert(
+            "b".to_string(),
+            1,
+            Object::from(bytes::Bytes::from_static(b"b")),
+        );
+        let got = cache.get("b").expect("entry not found");
+        assert_eq!(got.object.data, bytes::Bytes::from_static(b"b"));
+        assert_eq!(got.source, 1);
+        assert!(cache.is_fresh(&got));
+
+        cache.insert(
+            "c".to_string(),
+            0,
+            Object::from(bytes::Bytes::from_static(b"c")),
+        );
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some());
+        assert!(cache.get("c").is_some());
+
+        cache.remove("b");
+        assert!(cache.get("b").is_none());
+
+        // Entries are evicted by bytes too, and one larger than the limit is not kept.
+        let small = Cache::new(cache::Config {
+            ttl_seconds: 60,
+            max_entries: 10,
+            max_bytes: Some(4),
+        });
+        for (key, data) in [("a", b"aa"), ("b", b"bb")] {
+            small.insert(
+                key.to_string(),
+                0,
+                Object::from(bytes::Bytes::from_static(data)),
+            );
+        }
+        assert!(small.get("a").is_some());
+        small.insert(
+            "c".to_string(),
+            0,
+            Object::from(bytes::Bytes::from_static(b"cc")),
+        );
+        assert!(small.get("a").is_some());
+        assert!(small.get("b").is_none());
+        assert!(small.get("c").is_some());
+        small.insert(
+            "a".to_string(),
+            0,
+            Object::from(bytes::Bytes::from_static(b"a")),
+        );
+        assert_eq!(small.entries.lock().unwrap().bytes, 3);
+        small.insert(
+            "d".to_string(),
+            0,
+            Object::from(bytes::Bytes::from_static(b"ddddd")),
+        );
+        assert!(small.get("d").is_none());
+        assert_eq!(small.entries.lock().unwrap().bytes, 3);
+
+        let stale = Cache::new(cache::Config {
+            ttl_seconds: 0,
+            max_entries: 2,
+            max_bytes: None,
+        });
+        stale.insert(
+            "a".to_string(),
+            0,
+            Object::from(bytes::Bytes::from_static(b"a")),
+        );
+        let got = stale.get("a").expect("entry not found");
+        assert!(!stale.is_fresh(&got));
+        stale.touch("a");
+        assert!(stale.get("a").is_some());
+    }
+}
@@ -0,0 +1,115 @@
+use super::super::config::circuit_breaker;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { since: Instant },
+}
+
+impl CircuitBreaker {
+    pub fn new(cfg: circuit_breaker::Config) -> Self {
+        Self {
+            failure_threshold: cfg.failure_threshold.max(1),
+            open_duration: Duration::from_secs(cfg.open_seconds),
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    // https://martinfowler.com/bliki/CircuitBreaker.html
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now >= until => {
+                *state = State::HalfOpen { since: now };
+                true
+            }
+            State::Open { .. } => false,
+            // A probe whose future was dropped must not keep the circuit half-open forever.
+            State::HalfOpen { since } if now >= since + self.open_duration => {
+                *state = State::HalfOpen { since: now };
+                true
+            }
+            State::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state = State::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let until = Instant::now() + self.open_duration;
+        *state = match *state {
+            State::Closed { failures } if failures + 1 < self.failure_threshold => State::Closed {
+                failures: failures + 1,
+            },
+            State::Closed { .. } | State::HalfOpen { .. } => {
+                tracing::warn!("circuit breaker opened for {:?}", self.open_duration);
+                State::Open { until }
+            }
+            State::Open { .. } => State::Open { until },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(circuit_breaker::Config {
+            failure_threshold: 2,
+            open_seconds: 0,
+        });
+        assert!(breaker.try_acquire());
+        breaker.record_failure();
+        assert!(breaker.try_acquire());
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(breaker.try_acquire());
+        breaker.record_failure();
+        assert!(matches!(*breaker.state.lock().unwrap(), State::Open { .. }));
+
+        // The open duration is zero, so the next call becomes a half-open probe.
+        assert!(breaker.try_acquire());
+        assert!(matches!(
+            *breaker.state.lock().unwrap(),
+            State::HalfOpen { .. }
+        ));
+        breaker.record_failure();
+        assert!(matches!(*breaker.state.lock().unwrap(), State::Open { .. }));
+        assert!(breaker.try_acquire());
+        breaker.record_success();
+        assert_eq!(
+            *breaker.state.lock().unwrap(),
+            State::Closed { failures: 0 }
+        );
+    }
+
+    #[test]
+    fn test_circuit_breaker_fails_fast_while_open() {
+        let breaker = CircuitBreaker::new(circuit_breaker::Config {
+            failure_threshold: 1,
+            open_seconds: 60,
+        });
+        assert!(breaker.try_acquire());
+        breaker.record_failure();
+        assert!(!breaker.try_acquire());
+        assert!(!breaker.try_acquire());
+    }
+}
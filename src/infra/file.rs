@@ -9,9 +9,9 @@ impl Client {
     pub async fn read<P: AsRef<std::path::Path>>(
         &self,
         path: P,
-    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    ) -> Result<Option<bytes::Bytes>, Box<dyn std::error::Error>> {
         match tokio::fs::read(path).await {
-            Ok(content) => Ok(Some(content)),
+            Ok(content) => Ok(Some(bytes::Bytes::from(content))),
             Err(err) => {
                 if err.kind() == std::io::ErrorKind::NotFound {
                     Ok(None)
@@ -21,6 +21,14 @@ impl Client {
             }
         }
     }
+
+    pub async fn probe<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let _ = tokio::fs::metadata(path).await?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
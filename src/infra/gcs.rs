@@ -0,0 +1,295 @@
+use super::super::config::{gcs, web};
+use super::retry;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_ENDPOINT_URL: &str = "https://storage.googleapis.com";
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_only";
+const TOKEN_LIFETIME: u64 = 3600;
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+// https://cloud.google.com/storage/docs/request-endpoints#encoding
+const OBJECT_NAME_SET: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'.')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: reqwest::Client,
+    endpoint_url: String,
+    auth: Auth,
+    token: Arc<tokio::sync::Mutex<Option<Token>>>,
+    retry: retry::Policy,
+}
+
+#[derive(Clone, Debug)]
+enum Auth {
+    Anonymous,
+    ServiceAccount(ServiceAccountKey),
+    MetadataServer,
+}
+
+// https://cloud.google.com/iam/docs/keys-create-delete
+#[derive(Clone, Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+impl std::fmt::Debug for ServiceAccountKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServiceAccountKey")
+            .field("client_email", &self.client_email)
+            .field("token_uri", &self.token_uri)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    value: String,
+    expires_at: Instant,
+}
+
+impl Client {
+    pub fn new(cfg: gcs::Config, http: &web::Config, retry: retry::Policy) -> Self {
+        let auth = if cfg.anonymous.is_some_and(|v| v) {
+            Auth::Anonymous
+        } else if let Some(path) = cfg.credentials_path {
+            let file = std::fs::File::open(path).expect("failed to open gcs credentials");
+            let key = serde_json::from_reader(std::io::BufReader::new(file))
+                .expect("failed to read gcs credentials");
+            Auth::ServiceAccount(key)
+        } else {
+            Auth::MetadataServer
+        };
+        let endpoint_url = cfg
+            .endpoint_url
+            .unwrap_or_else(|| DEFAULT_ENDPOINT_URL.to_string())
+            .trim_end_matches("/")
+            .to_string();
+        Self {
+            http: super::web::build_http_client(http),
+            endpoint_url,
+            auth,
+            token: Arc::new(tokio::sync::Mutex::new(None)),
+            retry,
+        }
+    }
+
+    pub async fn get_object(
+        &self,
+        bucket: String,
+        name: String,
+    ) -> Result<Option<bytes::Bytes>, Box<dyn std::error::Error>> {
+        self.retry.run(|| self.try_get_object(&bucket, &name)).await
+    }
+
+    async fn try_get_object(
+        &self,
+        bucket: &str,
+        name: &str,
+    ) -> Result<Option<bytes::Bytes>, Box<dyn std::error::Error>> {
+        // https://cloud.google.com/storage/docs/json_api/v1/objects/get
+        let url = format!(
+            "{}/storage/v1/b/{bucket}/o/{}?alt=media",
+            self.endpoint_url,
+            percent_encoding::utf8_percent_encode(name, OBJECT_NAME_SET),
+        );
+        let mut req = self.http.get(&url);
+        if let Some(token) = self.access_token().await? {
+            req = req.bearer_auth(token);
+        }
+        let response = req.send().await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response.bytes().await?)),
+            status => Err(Box::from(format!("gs://{bucket}/{name} {status}"))),
+        }
+    }
+
+    async fn access_token(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        if matches!(self.auth, Auth::Anonymous) {
+            return Ok(None);
+        }
+        let mut cached = self.token.lock().await;
+        if let Some(token) = cached.as_ref() {
+            if Instant::now() + TOKEN_REFRESH_MARGIN < token.expires_at {
+                return Ok(Some(token.value.clone()));
+            }
+        }
+        let response = match &self.auth {
+            Auth::ServiceAccount(key) => self.exchange_jwt(key).await?,
+            Auth::MetadataServer => {
+                // https://cloud.google.com/compute/docs/access/authenticate-workloads#applications
+                self.http
+                    .get(METADATA_TOKEN_URL)
+                    .header("Metadata-Flavor", "Google")
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<TokenResponse>()
+                    .await?
+            }
+            Auth::Anonymous => return Ok(None),
+        };
+        let token = Token {
+            value: response.access_token,
+            expires_at: Instant::now() + Duration::from_secs(response.expires_in),
+        };
+        let value = token.value.clone();
+        *cached = Some(token);
+        Ok(Some(value))
+    }
+
+    async fn exchange_jwt(
+        &self,
+        key: &ServiceAccountKey,
+    ) -> Result<TokenResponse, Box<dyn std::error::Error>> {
+        // https://developers.google.com/identity/protocols/oauth2/service-account#httprest
+        let iat = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let claims = Claims {
+            iss: key.client_email.as_str(),
+            scope: SCOPE,
+            aud: key.token_uri.as_str(),
+            iat,
+            exp: iat + TOKEN_LIFETIME,
+        };
+        let header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256);
+        let encoding_key = jsonwebtoken::EncodingKey::from_rsa_pem(key.private_key.as_bytes())?;
+        let assertion = jsonwebtoken::encode(&header, &claims, &encoding_key)?;
+        let params = [
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+            ("assertion", assertion.as_str()),
+        ];
+        let response = self
+            .http
+            .post(key.token_uri.as_str())
+            .form(&params)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<TokenResponse>()
+            .await?;
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+impl Client {
+    pub fn for_test(endpoint_url: String) -> Self {
+        let cfg = gcs::Config {
+            endpoint_url: Some(endpoint_url),
+            anonymous: Some(true),
+            ..Default::default()
+        };
+        let http = web::Config {
+            user_agent: "fanlin-rs/0.0.0".to_string(),
+            timeout: 1,
+        };
+        Self::new(cfg, &http, retry::Policy::new(None))
+    }
+}
+
+// A tiny stand-in for the JSON API of fake-gcs-server that serves files under a directory.
+#[cfg(test)]
+pub async fn run_mock_server(bucket: &str, dir: &str) -> (u16, tokio::task::JoinHandle<()>) {
+    use axum::extract::Path;
+    use axum::http::StatusCode;
+    let root = std::path::PathBuf::from(dir);
+    let bucket = bucket.to_string();
+    let router = axum::Router::new().route(
+        "/storage/v1/b/{bucket}/o/{name}",
+        axum::routing::get(move |Path((b, name)): Path<(String, String)>| {
+            let root = root.clone();
+            let bucket = bucket.clone();
+            async move {
+                if b != bucket {
+                    return Err(StatusCode::NOT_FOUND);
+                }
+                let name = name.trim_start_matches("images/");
+                tokio::fs::read(root.join(name))
+                    .await
+                    .map_err(|_| StatusCode::NOT_FOUND)
+            }
+        }),
+    );
+    let addr = std::net::SocketAddr::from((std::net::Ipv4Addr::new(127, 0, 0, 1), 0));
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let task_handler = tokio::spawn(async move {
+        axum::serve(listener, router).await.unwrap();
+    });
+    (port, task_handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_get_object() {
+        let (port, mock_server) = run_mock_server("local-test", "images").await;
+        let cli = Client::for_test(format!("http://127.0.0.1:{port}"));
+
+        let got = cli
+            .get_object("local-test".to_string(), "images/lenna.png".to_string())
+            .await
+            .expect("failed to get an object");
+        assert_eq!(got, Some(std::fs::read("images/lenna.png").unwrap().into()));
+
+        let got = cli
+            .get_object("local-test".to_string(), "images/who.png".to_string())
+            .await
+            .expect("failed to get an object");
+        assert_eq!(got, None);
+
+        let got = cli
+            .get_object("unknown".to_string(), "images/lenna.png".to_string())
+            .await
+            .expect("failed to get an object");
+        assert_eq!(got, None);
+
+        mock_server.abort();
+
+        // A server which never answers is given up on by the timeout of the web client.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stalled_server = tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+        let cli = Client::for_test(format!("http://127.0.0.1:{port}"));
+        let getting = cli.get_object("local-test".to_string(), "images/lenna.png".to_string());
+        let got = tokio::time::timeout(std::time::Duration::from_secs(10), getting)
+            .await
+            .expect("timeout did not work");
+        assert!(got.is_err());
+
+        stalled_server.abort();
+    }
+}
@@ -1,22 +1,87 @@
+pub mod azure;
+pub mod cache;
+pub mod circuit_breaker;
 pub mod file;
+pub mod gcs;
+pub mod retry;
 pub mod s3;
 pub mod web;
 
 use super::config;
+use std::collections::HashMap;
+
+// An original along with the validators an origin gives to revalidate it later.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Object {
+    pub data: bytes::Bytes,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Revalidated {
+    NotModified,
+    Modified(Object),
+}
+
+impl From<bytes::Bytes> for Object {
+    fn from(data: bytes::Bytes) -> Self {
+        Self {
+            data,
+            ..Default::default()
+        }
+    }
+}
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Chunks of an original relayed from an origin without buffering.
+pub type Chunks =
+    std::pin::Pin<Box<dyn futures_util::Stream<Item = Result<bytes::Bytes, BoxError>> + Send>>;
 
 #[derive(Clone, Debug)]
 pub struct Client {
     pub s3: s3::Client,
     pub web: web::Client,
     pub file: file::Client,
+    pub gcs: Option<gcs::Client>,
+    pub azure: Option<azure::Client>,
+    s3_clients: HashMap<String, s3::Client>,
 }
 
 impl Client {
     pub async fn new(cfg: &config::Config) -> Self {
+        let retry = retry::Policy::new(cfg.client.retry.clone());
+        let mut s3_clients = HashMap::new();
+        if let Some(named) = &cfg.client.s3_clients {
+            for (name, c) in named.iter() {
+                let cli = s3::Client::new(c.clone(), retry.clone()).await;
+                s3_clients.insert(name.clone(), cli);
+            }
+        }
         Self {
-            s3: s3::Client::new(cfg.client.s3.clone()).await,
-            web: web::Client::new(cfg.client.web.clone()),
+            s3: s3::Client::new(cfg.client.s3.clone(), retry.clone()).await,
+            web: web::Client::new(cfg.client.web.clone(), retry.clone()),
             file: file::Client::new(),
+            gcs: cfg
+                .client
+                .gcs
+                .clone()
+                .map(|c| gcs::Client::new(c, &cfg.client.web, retry.clone())),
+            azure: cfg
+                .client
+                .azure
+                .clone()
+                .map(|c| azure::Client::new(c, &cfg.client.web, retry)),
+            s3_clients,
+        }
+    }
+
+    pub fn s3_client(&self, name: Option<&str>) -> Option<&s3::Client> {
+        match name {
+            Some(n) => self.s3_clients.get(n),
+            None => Some(&self.s3),
         }
     }
 }
@@ -28,6 +93,24 @@ impl Client {
             s3: s3::Client::for_test().await,
             web: web::Client::for_test(),
             file: file::Client::for_test(),
+            gcs: None,
+            azure: None,
+            s3_clients: HashMap::new(),
         }
     }
+
+    pub fn with_gcs_client(mut self, cli: gcs::Client) -> Self {
+        self.gcs = Some(cli);
+        self
+    }
+
+    pub fn with_azure_client(mut self, cli: azure::Client) -> Self {
+        self.azure = Some(cli);
+        self
+    }
+
+    pub fn with_s3_client(mut self, name: &str, cli: s3::Client) -> Self {
+        self.s3_clients.insert(name.to_string(), cli);
+        self
+    }
 }
@@ -0,0 +1,133 @@
+use super::super::config::retry;
+use rand::Rng;
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct Policy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Policy {
+    pub fn new(cfg: Option<retry::Config>) -> Self {
+        match cfg {
+            Some(c) => Self {
+                max_attempts: c.max_attempts.max(1),
+                base_delay: Duration::from_millis(c.base_delay_ms),
+                max_delay: Duration::from_millis(c.max_delay_ms),
+            },
+            None => Self {
+                max_attempts: 1,
+                base_delay: Duration::ZERO,
+                max_delay: Duration::ZERO,
+            },
+        }
+    }
+
+    // Errors are treated as transient and retried; callers map permanent
+    // failures such as not-found into Ok values before they reach here.
+    pub async fn run<T, E, F, Fut>(&self, mut f: F) -> Result<T, E>
+    where
+        E: std::fmt::Debug,
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let delay = match f().await {
+                Ok(v) => return Ok(v),
+                Err(err) if attempt < self.max_attempts => {
+                    let delay = self.backoff(attempt);
+                    tracing::warn!(
+                        "retrying in {delay:?} ({attempt}/{}); {err:?}",
+                        self.max_attempts
+                    );
+                    delay
+                }
+                Err(err) => return Err(err),
+            };
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        // https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        let ceil = self.base_delay.saturating_mul(factor).min(self.max_delay);
+        if ceil.is_zero() {
+            return ceil;
+        }
+        let millis = rand::thread_rng().gen_range(0..=ceil.as_millis() as u64);
+        Duration::from_millis(millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = Policy::new(Some(retry::Config {
+            max_attempts: 5,
+            base_delay_ms: 100,
+            max_delay_ms: 300,
+        }));
+        for _ in 0..100 {
+            assert!(policy.backoff(1) <= Duration::from_millis(100));
+            assert!(policy.backoff(2) <= Duration::from_millis(200));
+            assert!(policy.backoff(3) <= Duration::from_millis(300));
+            assert!(policy.backoff(64) <= Duration::from_millis(300));
+        }
+        assert_eq!(Policy::new(None).backoff(1), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let policy = Policy::new(Some(retry::Config {
+            max_attempts: 3,
+            base_delay_ms: 1,
+            max_delay_ms: 1,
+        }));
+
+        let mut calls = 0;
+        let got: Result<u32, &str> = policy
+            .run(|| {
+                calls += 1;
+                let n = calls;
+                async move {
+                    if n < 3 {
+                        Err("transient")
+                    } else {
+                        Ok(n)
+                    }
+                }
+            })
+            .await;
+        assert_eq!(got, Ok(3));
+
+        let mut calls = 0;
+        let got: Result<u32, &str> = policy
+            .run(|| {
+                calls += 1;
+                async { Err("down") }
+            })
+            .await;
+        assert_eq!(got, Err("down"));
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let got: Result<u32, &str> = Policy::new(None)
+            .run(|| {
+                calls += 1;
+                async { Err("down") }
+            })
+            .await;
+        assert_eq!(got, Err("down"));
+        assert_eq!(calls, 1);
+    }
+}
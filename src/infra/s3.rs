@@ -1,16 +1,28 @@
 use super::super::config::s3;
+use super::retry;
+use super::{BoxError, Chunks, Object, Revalidated};
 use aws_config;
+use aws_config::provider_config::ProviderConfig;
+use aws_config::sts::AssumeRoleProvider;
+use aws_config::web_identity_token::{StaticConfiguration, WebIdentityTokenCredentialsProvider};
+use aws_credential_types::provider::SharedCredentialsProvider;
 use aws_credential_types::Credentials;
 use aws_sdk_s3;
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::error::SdkError;
 use aws_sdk_s3::operation::get_object::GetObjectError;
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
+
+const DEFAULT_ROLE_SESSION_NAME: &str = "fanlin-rs";
 
 #[derive(Clone, Debug)]
 pub struct Client {
     s3: aws_sdk_s3::Client,
+    retry: retry::Policy,
 }
 
 impl Client {
-    pub async fn new(cfg: s3::Config) -> Self {
+    pub async fn new(cfg: s3::Config, retry: retry::Policy) -> Self {
         let aws_cfg = Self::make_aws_config(cfg.clone()).await;
         let mut aws_s3_cfg = aws_sdk_s3::config::Builder::from(&aws_cfg);
         if cfg.aws_endpoint_url.is_some() {
@@ -18,51 +30,205 @@ impl Client {
         };
         Self {
             s3: aws_sdk_s3::Client::from_conf(aws_s3_cfg.build()),
+            retry,
         }
     }
 
     async fn make_aws_config(cfg: s3::Config) -> aws_config::SdkConfig {
-        if cfg.aws_endpoint_url.is_none() {
-            return aws_config::from_env()
-                .region(aws_config::Region::new(cfg.aws_region))
-                .load()
-                .await;
+        // https://docs.rs/aws-config/latest/aws_config/struct.ConfigLoader.html
+        let region = aws_config::Region::new(cfg.aws_region);
+        let mut loader = aws_config::from_env().region(region.clone());
+        if let Some(url) = cfg.aws_endpoint_url {
+            loader = loader.endpoint_url(url);
+        }
+        if let Some(name) = cfg.aws_profile {
+            loader = loader.profile_name(name);
+        }
+        match (cfg.aws_access_key_id, cfg.aws_secret_access_key) {
+            (Some(id), Some(secret)) => {
+                loader = loader.credentials_provider(Credentials::from_keys(id, secret, None));
+            }
+            (None, None) => {}
+            _ => tracing::warn!(
+                "both aws_access_key_id and aws_secret_access_key are required, fall back to default credentials"
+            ),
         }
+        let base = loader.load().await;
+        let role_arn = match cfg.aws_role_arn {
+            Some(arn) => arn,
+            None => {
+                if cfg.aws_web_identity_token_file.is_some() {
+                    tracing::warn!("aws_web_identity_token_file is ignored without aws_role_arn");
+                }
+                return base;
+            }
+        };
+        let session_name = cfg
+            .aws_role_session_name
+            .unwrap_or_else(|| DEFAULT_ROLE_SESSION_NAME.to_string());
+        let provider = match cfg.aws_web_identity_token_file {
+            // https://docs.rs/aws-config/latest/aws_config/web_identity_token/index.html
+            Some(token_file) => {
+                let provider_cfg = ProviderConfig::default().with_region(Some(region));
+                let static_cfg = StaticConfiguration {
+                    web_identity_token_file: token_file.into(),
+                    role_arn,
+                    session_name,
+                };
+                SharedCredentialsProvider::new(
+                    WebIdentityTokenCredentialsProvider::builder()
+                        .configure(&provider_cfg)
+                        .static_configuration(static_cfg)
+                        .build(),
+                )
+            }
+            // https://docs.rs/aws-config/latest/aws_config/sts/struct.AssumeRoleProvider.html
+            None => {
+                let mut builder = AssumeRoleProvider::builder(role_arn)
+                    .session_name(session_name)
+                    .configure(&base);
+                if let Some(id) = cfg.aws_external_id {
+                    builder = builder.external_id(id);
+                }
+                SharedCredentialsProvider::new(builder.build().await)
+            }
+        };
+        base.into_builder().credentials_provider(provider).build()
+    }
 
-        let creds = Credentials::from_keys(
-            cfg.aws_access_key_id.expect("aws_access_key_id required"),
-            cfg.aws_secret_access_key
-                .expect("aws_secret_access_key required"),
-            None,
-        );
-        aws_config::from_env()
-            .endpoint_url(cfg.aws_endpoint_url.expect("aws_endpoint_url required"))
-            .region(aws_config::Region::new(cfg.aws_region))
-            .credentials_provider(creds)
-            .load()
+    pub async fn revalidate_object(
+        &self,
+        bucket: String,
+        key: String,
+        cached: Option<&Object>,
+    ) -> Result<Option<Revalidated>, Box<dyn std::error::Error>> {
+        self.retry
+            .run(|| self.try_get_object(&bucket, &key, cached))
             .await
     }
 
-    pub async fn get_object(
+    // Checks that the bucket is reachable with the credentials, without retrying.
+    pub async fn head_bucket(&self, bucket: &str) -> Result<(), Box<dyn std::error::Error>> {
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_HeadBucket.html
+        let _ = self.s3.head_bucket().bucket(bucket).send().await?;
+        Ok(())
+    }
+
+    pub async fn put_object(
+        &self,
+        bucket: String,
+        key: String,
+        body: bytes::Bytes,
+        content_type: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // https://docs.rs/aws-sdk-s3/latest/aws_sdk_s3/client/struct.Client.html#method.put_object
+        let _ = self
+            .s3
+            .put_object()
+            .bucket(bucket)
+            .key(key)
+            .set_content_type(content_type.map(|v| v.to_string()))
+            .body(aws_sdk_s3::primitives::ByteStream::from(body))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn open_object(
         &self,
         bucket: String,
         key: String,
-    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    ) -> Result<Option<Chunks>, Box<dyn std::error::Error>> {
+        self.retry.run(|| self.try_open_object(&bucket, &key)).await
+    }
+
+    async fn try_open_object(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<Option<Chunks>, Box<dyn std::error::Error>> {
+        match self.s3.get_object().bucket(bucket).key(key).send().await {
+            Ok(output) => {
+                // https://docs.rs/aws-sdk-s3/latest/aws_sdk_s3/primitives/struct.ByteStream.html#method.next
+                let chunks = futures_util::stream::unfold(output.body, |mut body| async move {
+                    let chunk = body.next().await?;
+                    Some((chunk.map_err(BoxError::from), body))
+                });
+                Ok(Some(Box::pin(chunks)))
+            }
+            Err(sdk_err) => classify(bucket, key, sdk_err),
+        }
+    }
+
+    async fn try_get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        cached: Option<&Object>,
+    ) -> Result<Option<Revalidated>, Box<dyn std::error::Error>> {
         // https://docs.rs/aws-sdk-s3/latest/aws_sdk_s3/client/struct.Client.html#method.get_object
         // https://docs.rs/aws-sdk-s3/latest/aws_sdk_s3/primitives/struct.ByteStream.html
-        match self.s3.get_object().bucket(bucket).key(key).send().await {
+        let if_modified_since = cached
+            .and_then(|c| c.last_modified.as_deref())
+            .and_then(|v| DateTime::from_str(v, DateTimeFormat::HttpDate).ok());
+        let request = self
+            .s3
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .set_if_none_match(cached.and_then(|c| c.etag.clone()))
+            .set_if_modified_since(if_modified_since);
+        match request.send().await {
             Ok(output) => {
-                let mut buffer = output
-                    .content_length
-                    .map_or_else(Vec::new, |size| Vec::with_capacity(size as usize));
-                let mut reader = output.body.into_async_read();
-                let _ = tokio::io::copy_buf(&mut reader, &mut buffer).await?;
-                Ok(Some(buffer))
+                let etag = output.e_tag.clone();
+                let last_modified = output
+                    .last_modified
+                    .and_then(|v| v.fmt(DateTimeFormat::HttpDate).ok());
+                let content_type = output.content_type.clone();
+                let data = output.body.collect().await?.into_bytes();
+                Ok(Some(Revalidated::Modified(Object {
+                    data,
+                    etag,
+                    last_modified,
+                    content_type,
+                })))
             }
-            Err(sdk_err) => match sdk_err.into_service_error() {
-                GetObjectError::NoSuchKey(_) => Ok(None),
-                err => Err(Box::from(err)),
-            },
+            // https://docs.aws.amazon.com/AmazonS3/latest/API/API_GetObject.html#API_GetObject_RequestSyntax
+            Err(sdk_err)
+                if sdk_err
+                    .raw_response()
+                    .is_some_and(|r| r.status().as_u16() == 304) =>
+            {
+                Ok(Some(Revalidated::NotModified))
+            }
+            Err(sdk_err) => classify(bucket, key, sdk_err),
+        }
+    }
+}
+
+// As with HTTP origins, only failures worth retrying are errors, and the others are regarded as not found
+// so that they are neither retried nor counted by a circuit breaker.
+fn classify<T>(
+    bucket: &str,
+    key: &str,
+    sdk_err: SdkError<GetObjectError, HttpResponse>,
+) -> Result<Option<T>, Box<dyn std::error::Error>> {
+    let transient = match &sdk_err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => true,
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/ErrorResponses.html
+        _ => sdk_err.raw_response().is_some_and(|r| {
+            let status = r.status().as_u16();
+            status == 429 || status >= 500
+        }),
+    };
+    if transient {
+        return Err(Box::from(sdk_err));
+    }
+    match sdk_err.into_service_error() {
+        GetObjectError::NoSuchKey(_) => Ok(None),
+        err => {
+            tracing::warn!("s3://{bucket}/{key} {err:?}");
+            Ok(None)
         }
     }
 }
@@ -75,11 +241,23 @@ impl Client {
             aws_endpoint_url: Some("http://127.0.0.1:4567".to_string()),
             aws_access_key_id: Some("AAAAAAAAAAAAAAAAAAAA".to_string()),
             aws_secret_access_key: Some("AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string()),
+            ..Default::default()
         };
-        Self::new(cfg.clone()).await
+        Self::new(cfg.clone(), retry::Policy::new(None)).await
+    }
+
+    pub async fn get_object(
+        &self,
+        bucket: String,
+        key: String,
+    ) -> Result<Option<Object>, Box<dyn std::error::Error>> {
+        match self.revalidate_object(bucket, key, None).await? {
+            Some(Revalidated::Modified(object)) => Ok(Some(object)),
+            _ => Ok(None),
+        }
     }
 
-    async fn put_object<P: AsRef<std::path::Path>>(
+    async fn upload_file<P: AsRef<std::path::Path>>(
         &self,
         bucket: &String,
         key: &String,
@@ -168,7 +346,7 @@ impl BucketManager {
             if dir_entry.file_type()?.is_file() {
                 let file = dir_entry.file_name().to_str().unwrap().to_string();
                 let key = format!("{path}/{file}");
-                self.cli.put_object(bucket, &key, dir_entry.path()).await?;
+                self.cli.upload_file(bucket, &key, dir_entry.path()).await?;
             }
         }
         Ok(())
@@ -181,3 +359,187 @@ impl BucketManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_new_with_various_credentials() {
+        use aws_credential_types::provider::ProvideCredentials;
+        // Profiles are read from files of this test instead of those of the environment.
+        let dir = std::env::temp_dir().join(format!("fanlin-aws-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let credentials = dir.join("credentials");
+        std::fs::write(
+            &credentials,
+            "[default]\naws_access_key_id = DEFAULTDEFAULTDEFAULT\naws_secret_access_key = default\n\
+             [fanlin]\naws_access_key_id = PROFILEPROFILEPROFILE\naws_secret_access_key = profile\n",
+        )
+        .unwrap();
+        std::env::set_var("AWS_SHARED_CREDENTIALS_FILE", &credentials);
+        std::env::set_var("AWS_CONFIG_FILE", dir.join("config"));
+        for name in [
+            "AWS_ACCESS_KEY_ID",
+            "AWS_SECRET_ACCESS_KEY",
+            "AWS_SESSION_TOKEN",
+            "AWS_PROFILE",
+        ] {
+            std::env::remove_var(name);
+        }
+
+        // Each case gives the access key resolved, or the provider assuming a role.
+        let cases = [
+            (
+                s3::Config {
+                    aws_region: "ap-northeast-1".to_string(),
+                    aws_endpoint_url: Some("http://127.0.0.1:4567".to_string()),
+                    ..Default::default()
+                },
+                "DEFAULTDEFAULTDEFAULT",
+            ),
+            (
+                s3::Config {
+                    aws_region: "ap-northeast-1".to_string(),
+                    aws_access_key_id: Some("AAAAAAAAAAAAAAAAAAAA".to_string()),
+                    ..Default::default()
+                },
+                "DEFAULTDEFAULTDEFAULT",
+            ),
+            (
+                s3::Config {
+                    aws_region: "ap-northeast-1".to_string(),
+                    aws_access_key_id: Some("AAAAAAAAAAAAAAAAAAAA".to_string()),
+                    aws_secret_access_key: Some(
+                        "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string(),
+                    ),
+                    ..Default::default()
+                },
+                "AAAAAAAAAAAAAAAAAAAA",
+            ),
+            (
+                s3::Config {
+                    aws_region: "ap-northeast-1".to_string(),
+                    aws_profile: Some("fanlin".to_string()),
+                    ..Default::default()
+                },
+                "PROFILEPROFILEPROFILE",
+            ),
+            (
+                s3::Config {
+                    aws_region: "ap-northeast-1".to_string(),
+                    aws_role_arn: Some("arn:aws:iam::123456789012:role/fanlin".to_string()),
+                    aws_external_id: Some("dummy".to_string()),
+                    ..Default::default()
+                },
+                "AssumeRoleProvider",
+            ),
+            (
+                s3::Config {
+                    aws_region: "ap-northeast-1".to_string(),
+                    aws_role_arn: Some("arn:aws:iam::123456789012:role/fanlin".to_string()),
+                    aws_web_identity_token_file: Some("/var/run/secrets/token".to_string()),
+                    ..Default::default()
+                },
+                "WebIdentityTokenCredentialsProvider",
+            ),
+        ];
+        for (c, want) in cases {
+            let aws_cfg = Client::make_aws_config(c.clone()).await;
+            assert_eq!(
+                aws_cfg.region().map(|r| r.as_ref()),
+                Some("ap-northeast-1"),
+                "case: {c:?}"
+            );
+            let provider = aws_cfg
+                .credentials_provider()
+                .expect("credentials provider is missing");
+            if c.aws_role_arn.is_some() {
+                // Assuming a role needs STS, so only the kind of the provider is checked.
+                let got = format!("{provider:?}");
+                assert!(got.contains(want), "case: {c:?}, got: {got}");
+            } else {
+                let got = provider
+                    .provide_credentials()
+                    .await
+                    .expect("failed to resolve credentials");
+                assert_eq!(got.access_key_id(), want, "case: {c:?}");
+            }
+            let _ = Client::new(c, retry::Policy::new(None)).await;
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_object_errors() {
+        let cli = Client::for_test().await;
+        let got = cli
+            .get_object("no-such-bucket".to_string(), "lenna.png".to_string())
+            .await
+            .expect("a missing bucket is not transient");
+        assert_eq!(got, None);
+
+        let unreachable = Client::new(
+            s3::Config {
+                aws_region: "ap-northeast-1".to_string(),
+                aws_endpoint_url: Some("http://127.0.0.1:1".to_string()),
+                aws_access_key_id: Some("AAAAAAAAAAAAAAAAAAAA".to_string()),
+                aws_secret_access_key: Some("AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string()),
+                ..Default::default()
+            },
+            retry::Policy::new(None),
+        )
+        .await;
+        assert!(unreachable
+            .get_object("local-test".to_string(), "lenna.png".to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_revalidate_object() {
+        let cli = Client::for_test().await;
+        let mut bucket_manager = BucketManager::new(cli.clone());
+        let bucket = bucket_manager
+            .create()
+            .await
+            .expect("failed to create a bucket");
+        let key = "images/lenna.png".to_string();
+        cli.upload_file(&bucket, &key, "images/lenna.png")
+            .await
+            .expect("failed to upload a file");
+
+        let got = cli
+            .get_object(bucket.clone(), key.clone())
+            .await
+            .expect("failed to get an object")
+            .expect("object not found");
+        assert_eq!(got.data, std::fs::read("images/lenna.png").unwrap());
+        assert!(got.etag.is_some());
+        assert!(got.last_modified.is_some());
+
+        let revalidated = cli
+            .revalidate_object(bucket.clone(), key.clone(), Some(&got))
+            .await
+            .expect("failed to revalidate an object");
+        assert_eq!(revalidated, Some(Revalidated::NotModified));
+
+        let stale = Object {
+            etag: Some("\"stale\"".to_string()),
+            ..Default::default()
+        };
+        let revalidated = cli
+            .revalidate_object(bucket.clone(), key.clone(), Some(&stale))
+            .await
+            .expect("failed to revalidate an object");
+        assert_eq!(revalidated, Some(Revalidated::Modified(got)));
+
+        let revalidated = cli
+            .revalidate_object(bucket.clone(), "images/who.png".to_string(), Some(&stale))
+            .await
+            .expect("failed to revalidate an object");
+        assert_eq!(revalidated, None);
+
+        bucket_manager.clean().await.unwrap();
+    }
+}
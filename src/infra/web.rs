@@ -1,42 +1,148 @@
 use super::super::config::web;
-use reqwest::StatusCode;
+use super::retry;
+use super::{BoxError, Chunks, Object, Revalidated};
+use reqwest::{header, StatusCode};
 use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct Client {
     http: reqwest::Client,
+    retry: retry::Policy,
+}
+
+// Clients of HTTP based storages share the user agent and timeout of the web client.
+pub fn build_http_client(cfg: &web::Config) -> reqwest::Client {
+    reqwest::ClientBuilder::new()
+        .user_agent(cfg.user_agent.as_str())
+        .timeout(Duration::from_secs(cfg.timeout))
+        .build()
+        .expect("failed to build http client")
 }
 
 impl Client {
-    pub fn new(cfg: web::Config) -> Self {
-        let client = reqwest::ClientBuilder::new()
-            .user_agent(cfg.user_agent)
-            .timeout(Duration::from_secs(cfg.timeout));
+    pub fn new(cfg: web::Config, retry: retry::Policy) -> Self {
         Self {
-            http: client.build().expect("failed to build http client"),
+            http: build_http_client(&cfg),
+            retry,
         }
     }
 
-    pub async fn get(&self, url: String) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    pub async fn revalidate(
+        &self,
+        url: String,
+        cached: Option<&Object>,
+    ) -> Result<Option<Revalidated>, Box<dyn std::error::Error>> {
+        self.retry.run(|| self.try_get(&url, cached)).await
+    }
+
+    pub async fn open(&self, url: String) -> Result<Option<Chunks>, Box<dyn std::error::Error>> {
+        self.retry.run(|| self.try_open(&url)).await
+    }
+
+    async fn try_open(&self, url: &str) -> Result<Option<Chunks>, Box<dyn std::error::Error>> {
+        let Some(response) = self.try_send(url, None).await? else {
+            return Ok(None);
+        };
+        // https://docs.rs/reqwest/latest/reqwest/struct.Response.html#method.chunk
+        let chunks = futures_util::stream::unfold(response, |mut response| async move {
+            match response.chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), response)),
+                Ok(None) => None,
+                Err(err) => Some((Err(BoxError::from(err)), response)),
+            }
+        });
+        Ok(Some(Box::pin(chunks)))
+    }
+
+    async fn try_get(
+        &self,
+        url: &str,
+        cached: Option<&Object>,
+    ) -> Result<Option<Revalidated>, Box<dyn std::error::Error>> {
+        let Some(response) = self.try_send(url, cached).await? else {
+            return Ok(None);
+        };
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Some(Revalidated::NotModified));
+        }
+        let headers = response.headers();
+        let value = |name| {
+            headers
+                .get(name)
+                .and_then(|v: &header::HeaderValue| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let etag = value(header::ETAG);
+        let last_modified = value(header::LAST_MODIFIED);
+        let content_type = value(header::CONTENT_TYPE);
+        let data = response.bytes().await?;
+        Ok(Some(Revalidated::Modified(Object {
+            data,
+            etag,
+            last_modified,
+            content_type,
+        })))
+    }
+
+    // Any answer other than a server error tells that the origin is reachable.
+    // Errors leave out the url, since they are shown in a public readiness report.
+    pub async fn probe(&self, url: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let response = self
+            .http
+            .head(url)
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+        if response.status().is_server_error() {
+            return Err(Box::from(response.status().to_string()));
+        }
+        Ok(())
+    }
+
+    // Gives a response to read an image from, which is either successful or not modified.
+    async fn try_send(
+        &self,
+        url: &str,
+        cached: Option<&Object>,
+    ) -> Result<Option<reqwest::Response>, Box<dyn std::error::Error>> {
+        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Conditional_requests
+        let mut request = self
+            .http
+            .get(url)
+            .headers(super::super::telemetry::propagation_headers());
+        if let Some(etag) = cached.and_then(|c| c.etag.as_deref()) {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = cached.and_then(|c| c.last_modified.as_deref()) {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
         // https://docs.rs/reqwest/latest/reqwest/struct.Client.html
-        match self.http.get(&url).send().await {
+        match request.send().await {
             // https://docs.rs/reqwest/latest/reqwest/struct.Response.html
             Ok(response) => {
                 if response.status() == StatusCode::NOT_FOUND {
                     return Ok(None);
                 }
+                if response.status() == StatusCode::NOT_MODIFIED {
+                    return Ok(Some(response));
+                }
+                if response.status().is_server_error() {
+                    return Err(Box::from(format!("{url} {}", response.status())));
+                }
                 if !response.status().is_success() {
                     tracing::warn!("{url} {}", response.status());
                     return Ok(None);
                 }
-                let bytes = response.bytes().await?;
-                Ok(Some(bytes.to_vec()))
+                Ok(Some(response))
             }
             // https://docs.rs/reqwest/latest/reqwest/struct.Error.html
             Err(err) => {
                 if err.status() == Some(StatusCode::NOT_FOUND) {
                     return Ok(None);
                 }
+                if err.is_timeout() || err.is_connect() || err.is_request() {
+                    return Err(Box::from(err));
+                }
                 tracing::warn!("{url} {err:?}");
                 Ok(None)
             }
@@ -51,7 +157,14 @@ impl Client {
             user_agent: "fanlin-rs/0.0.0".to_string(),
             timeout: 1,
         };
-        Self::new(cfg)
+        Self::new(cfg, retry::Policy::new(None))
+    }
+
+    pub async fn get(&self, url: String) -> Result<Option<Object>, Box<dyn std::error::Error>> {
+        match self.revalidate(url, None).await? {
+            Some(Revalidated::Modified(object)) => Ok(Some(object)),
+            _ => Ok(None),
+        }
     }
 }
 
@@ -66,3 +179,51 @@ pub async fn run_mock_server(path: &str, dir: &str) -> (u16, tokio::task::JoinHa
     });
     (port, task_handler)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_revalidate() {
+        let (port, mock_server) = run_mock_server("/images", "images").await;
+        let cli = Client::for_test();
+        let url = format!("http://127.0.0.1:{port}/images/lenna.png");
+
+        let got = cli
+            .get(url.clone())
+            .await
+            .expect("failed to get an image")
+            .expect("image not found");
+        assert_eq!(got.data, std::fs::read("images/lenna.png").unwrap());
+        assert_eq!(got.content_type, Some("image/png".to_string()));
+        assert!(got.last_modified.is_some());
+
+        let revalidated = cli
+            .revalidate(url.clone(), Some(&got))
+            .await
+            .expect("failed to revalidate an image");
+        assert_eq!(revalidated, Some(Revalidated::NotModified));
+
+        let stale = Object {
+            last_modified: Some("Sun, 06 Nov 1994 08:49:37 GMT".to_string()),
+            ..Default::default()
+        };
+        let revalidated = cli
+            .revalidate(url.clone(), Some(&stale))
+            .await
+            .expect("failed to revalidate an image");
+        assert_eq!(revalidated, Some(Revalidated::Modified(got)));
+
+        let revalidated = cli
+            .revalidate(
+                format!("http://127.0.0.1:{port}/images/who.png"),
+                Some(&stale),
+            )
+            .await
+            .expect("failed to revalidate an image");
+        assert_eq!(revalidated, None);
+
+        mock_server.abort();
+    }
+}
@@ -3,7 +3,7 @@ extern crate tokio;
 
 use axum::{
     body::Body,
-    extract::{OriginalUri, Query, State},
+    extract::{ConnectInfo, OriginalUri, Query, State},
     http::{header, StatusCode},
     response::IntoResponse,
     routing::get,
@@ -11,11 +11,20 @@ use axum::{
 use clap::Parser;
 use tracing_subscriber::prelude::*;
 
+mod access_log;
 mod config;
 mod content;
+mod cors;
 mod handler;
+mod health;
 mod infra;
+mod metrics;
+mod pool;
 mod query;
+mod rate_limit;
+mod telemetry;
+mod text;
+mod watermark;
 
 /// A web server to process and serve images
 #[derive(Parser, Debug)]
@@ -39,6 +48,9 @@ async fn main() {
             None => config::Config::from_file(args.conf).expect("failed to read a config file"),
         }
     };
+    let tracer_provider = cfg.telemetry.clone().map(|c| {
+        telemetry::init_tracer_provider(c).expect("failed to initialize a tracer provider")
+    });
     {
         // https://docs.rs/tracing-subscriber/latest/tracing_subscriber/fmt/struct.SubscriberBuilder.html
         let logger = tracing_subscriber::fmt::layer()
@@ -56,14 +68,21 @@ async fn main() {
                 }
             },
         );
+        let otel = tracer_provider.as_ref().map(|p| {
+            telemetry::layer(p).with_filter(tracing_subscriber::filter::LevelFilter::INFO)
+        });
         tracing_subscriber::registry()
             .with(logger.with_filter(level))
+            .with(otel)
             .init();
     }
     let mut state = {
         let cli = infra::Client::new(&cfg).await;
         handler::State::new(cfg.providers.clone(), cli)
     };
+    if let Some(c) = cfg.health.clone() {
+        state.configure_health_checks(c);
+    }
     state
         .with_fallback(&cfg.fallback_path, &cfg.providers)
         .await
@@ -71,6 +90,14 @@ async fn main() {
             |err| tracing::warn!("failed to initialize fallback image; {err:?}"),
             |_| {},
         );
+    // Images must not be served without an enforced watermark, so missing logos stop the server.
+    state
+        .with_watermarks(&cfg.providers)
+        .await
+        .expect("failed to load watermark images");
+    if let Some(c) = &cfg.text {
+        state.load_fonts(c).expect("failed to load fonts");
+    }
     if let Some(path) = cfg.profile_path {
         state.create_cmyk_to_rgb_converter(path).await;
     }
@@ -79,6 +106,31 @@ async fn main() {
             state.enable_embedded_profile_utilization();
         }
     }
+    if let Some(c) = cfg.cache.clone() {
+        state.enable_originals_cache(c);
+    }
+    if let Some(c) = cfg.processing.clone() {
+        state.configure_processing_pool(c);
+    }
+    if let Some(c) = cfg.timeout.clone() {
+        state.configure_timeouts(c);
+    }
+    if let Some(c) = cfg.rate_limit.clone() {
+        state.enable_rate_limit(c);
+    }
+    if let Some(list) = &cfg.trusted_proxies {
+        state.trust_proxies(list);
+    }
+    if let Some(c) = cfg.cors.clone() {
+        state.enable_cors(c);
+    }
+    if let Some(c) = cfg.access_log.clone() {
+        state.configure_access_log(c);
+    }
+    // The handler answers with a fallback on its own deadlines, so this layer is only a backstop.
+    let backstop_timeout = state.request_timeout() + TIMEOUT_GRACE;
+    state.metrics().set_max_clients(cfg.max_clients);
+    let state = std::sync::Arc::new(state);
     // https://github.com/tower-rs/tower-http/blob/main/examples/axum-key-value-store/src/main.rs
     // https://docs.rs/axum/latest/axum/middleware/index.html
     // https://docs.rs/tower-http/latest/tower_http/trace/index.html
@@ -87,115 +139,536 @@ async fn main() {
     // https://docs.rs/tracing/latest/tracing/span/struct.Span.html
     let router = axum::Router::new()
         .route("/ping", get(|| async { "pong" }))
+        .route("/metrics", get(metrics_handler))
+        .route(
+            "/healthz",
+            get(|| async { axum::Json(serde_json::json!({"ok": true})) }),
+        )
+        .route("/readyz", get(readyz_handler))
         .fallback(generic_handler)
         .layer(
             tower::ServiceBuilder::new()
                 .layer(
                     tower_http::trace::TraceLayer::new_for_http()
-                        .make_span_with(
-                            tower_http::trace::DefaultMakeSpan::new().level(tracing::Level::INFO),
-                        )
-                        .on_response(
-                            tower_http::trace::DefaultOnResponse::new()
-                                .level(tracing::Level::INFO)
-                                .latency_unit(tower_http::LatencyUnit::Millis),
-                        )
+                        .make_span_with(telemetry::make_span)
+                        .on_response(())
                         .on_failure(()),
                 )
-                .layer(tower_http::timeout::TimeoutLayer::new(
-                    std::time::Duration::from_secs(10),
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    write_access_log,
                 ))
+                .layer(axum::middleware::from_fn_with_state(state.clone(), track))
+                .layer(axum::middleware::from_fn_with_state(state.clone(), cors))
+                .layer(tower_http::timeout::TimeoutLayer::new(backstop_timeout))
                 .layer(tower::limit::concurrency::ConcurrencyLimitLayer::new(
                     cfg.max_clients,
+                ))
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    count_in_flight,
                 )),
         )
-        .with_state(std::sync::Arc::new(state));
+        .with_state(state);
     let listen_addr = format!("{}:{}", &cfg.bind_addr, &cfg.port);
     let listener = tokio::net::TcpListener::bind(&listen_addr)
         .await
         .expect("failed to bind address");
     tracing::info!("serving on {listen_addr}");
-    axum::serve(listener, router)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .expect("failed to start server");
+    // https://docs.rs/axum/latest/axum/extract/struct.ConnectInfo.html
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .expect("failed to start server");
+    if let Some(p) = tracer_provider {
+        if let Err(err) = p.shutdown() {
+            tracing::warn!("failed to shut down a tracer provider; {err:?}");
+        }
+    }
 }
 
 const CONTENT_TYPE_TEXT_PLAIN: &str = "text/plain; charset=utf-8";
 const VARY_ACCEPT: &str = "Accept";
+const CONTENT_TYPE_JSON: &str = "application/json";
+const INFO_PATH_PREFIX: &str = "/_info/";
+const TIMEOUT_GRACE: std::time::Duration = std::time::Duration::from_secs(1);
+
+// https://docs.rs/http/latest/http/uri/struct.Uri.html#method.host
+fn request_host<'a>(headers: &'a header::HeaderMap, uri: &'a axum::http::Uri) -> Option<&'a str> {
+    headers
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| uri.host())
+}
+
+async fn track(
+    State(state): State<std::sync::Arc<handler::State>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let metrics = state.metrics();
+    let host = request_host(request.headers(), request.uri());
+    let provider = state.provider_label(host, request.uri().path());
+    let response = next.run(request).await;
+    let (content_type, size) = describe_body(&response);
+    let content_type = content_type.unwrap_or_default();
+    metrics.observe_response(provider, response.status().as_u16(), content_type, size);
+    response
+}
+
+// Placed inside the concurrency limit so that requests waiting for a permit are not counted.
+async fn count_in_flight(
+    State(state): State<std::sync::Arc<handler::State>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let _in_flight = state.metrics().track_in_flight();
+    next.run(request).await
+}
+
+async fn write_access_log(
+    State(state): State<std::sync::Arc<handler::State>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let started = std::time::Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let mut response = next.run(request).await;
+    let logger = state.access_log();
+    if !logger.sampled(response.status()) {
+        return response;
+    }
+    let record = response
+        .extensions_mut()
+        .remove::<access_log::Record>()
+        .unwrap_or_default();
+    let (format, output_bytes) = describe_body(&response);
+    let outcome = access_log::Outcome {
+        method: &method,
+        path: path.as_str(),
+        status: response.status(),
+        latency: started.elapsed(),
+        format,
+        output_bytes,
+    };
+    logger.log(&outcome, &record);
+    response
+}
+
+fn describe_body(response: &axum::response::Response) -> (Option<&str>, Option<u64>) {
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    // https://docs.rs/http-body/latest/http_body/struct.SizeHint.html
+    let size = axum::body::HttpBody::size_hint(response.body()).exact();
+    (content_type, size)
+}
+
+// https://kubernetes.io/docs/reference/using-api/health-checks/
+async fn readyz_handler(State(state): State<std::sync::Arc<handler::State>>) -> impl IntoResponse {
+    let report = state.readiness().await;
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, axum::Json(report))
+}
+
+async fn metrics_handler(State(state): State<std::sync::Arc<handler::State>>) -> impl IntoResponse {
+    match state.metrics().render() {
+        Ok(text) => {
+            let content_type = header::HeaderValue::from_static(prometheus::TEXT_FORMAT);
+            (StatusCode::OK, [(header::CONTENT_TYPE, content_type)], text)
+        }
+        Err(err) => {
+            tracing::error!("failed to render metrics; {err:?}");
+            let content_type = header::HeaderValue::from_static(CONTENT_TYPE_TEXT_PLAIN);
+            let text = "failed to render metrics".to_string();
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CONTENT_TYPE, content_type)],
+                text,
+            )
+        }
+    }
+}
+
+// https://docs.rs/axum/latest/axum/middleware/fn.from_fn_with_state.html
+async fn cors(
+    State(state): State<std::sync::Arc<handler::State>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let host = request_host(request.headers(), request.uri());
+    let Some(policy) = state.cors_policy(host, request.uri().path()) else {
+        return next.run(request).await;
+    };
+    if cors::Policy::is_preflight(request.method(), request.headers()) {
+        let headers = policy.preflight(request.headers());
+        return (StatusCode::NO_CONTENT, headers).into_response();
+    }
+    let origin = request.headers().get(header::ORIGIN).cloned();
+    let mut response = next.run(request).await;
+    policy.apply(origin.as_ref(), response.headers_mut());
+    response
+}
 
 #[axum::debug_handler]
 async fn generic_handler(
+    ConnectInfo(peer): ConnectInfo<std::net::SocketAddr>,
     headers: header::HeaderMap,
     OriginalUri(uri): OriginalUri,
     Query(params): Query<query::Query>,
     State(state): State<std::sync::Arc<handler::State>>,
 ) -> impl IntoResponse {
+    let (uri, params) = match strip_info_prefix(&uri) {
+        Some(stripped) => (stripped, params.with_info()),
+        None => (uri, params),
+    };
+    let mut record = access_log::Record::default();
+    let (status, headers, body) =
+        serve_image(&state, &mut record, peer, &headers, &uri, params).await;
+    (status, headers, axum::Extension(record), body)
+}
+
+// `/_info/foo/x.jpg` is the same as `/foo/x.jpg?info=true`.
+fn strip_info_prefix(uri: &axum::http::Uri) -> Option<axum::http::Uri> {
+    let path = uri.path().strip_prefix(INFO_PATH_PREFIX)?;
+    let path_and_query = match uri.query() {
+        Some(q) => format!("/{path}?{q}"),
+        None => format!("/{path}"),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    axum::http::Uri::from_parts(parts).ok()
+}
+
+async fn serve_image(
+    state: &std::sync::Arc<handler::State>,
+    record: &mut access_log::Record,
+    peer: std::net::SocketAddr,
+    headers: &header::HeaderMap,
+    uri: &axum::http::Uri,
+    params: query::Query,
+) -> (StatusCode, header::HeaderMap, Body) {
+    let started = std::time::Instant::now();
+    let host = request_host(headers, uri);
+    let params = match state.default_query(host, uri.path()) {
+        Some(defaults) => params.with_defaults(&defaults),
+        None => params,
+    };
+    let provider = state.provider_label(host, uri.path());
+    if provider != metrics::NO_PROVIDER {
+        record.provider = Some(provider.to_string());
+    }
+    record.query = Some(params.normalized());
     if params.unsupported_scale_size() {
         let headers = create_header(CONTENT_TYPE_TEXT_PLAIN, &params, None);
         let message = format!("supported width and height: {}", query::size_range_info());
         return (StatusCode::BAD_REQUEST, headers, Body::from(message));
     }
+    match state.check_referral(host, uri.path(), headers) {
+        handler::Referral::Allowed => {}
+        handler::Referral::Forbidden => {
+            let headers = create_header(CONTENT_TYPE_TEXT_PLAIN, &params, None);
+            return (StatusCode::FORBIDDEN, headers, Body::from("forbidden"));
+        }
+        handler::Referral::Placeholder => {
+            let accepted_format = extract_accepted_image_formats(headers);
+            return fallback_or_message(
+                state,
+                record,
+                host,
+                uri.path(),
+                &params,
+                accepted_format,
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                started,
+            )
+            .await;
+        }
+    }
+    let accepted_format = extract_accepted_image_formats(headers);
+    let client = state.client_ip(peer.ip(), headers);
+    if let Err(wait) = state.check_rate_limit(host, uri.path(), client, &params, accepted_format) {
+        tracing::warn!("rate limited; {client} {}", uri.path());
+        let mut headers = create_header(CONTENT_TYPE_TEXT_PLAIN, &params, None);
+        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Retry-After
+        let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+        headers.insert(header::RETRY_AFTER, header::HeaderValue::from(seconds));
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            headers,
+            Body::from("too many requests"),
+        );
+    }
+    let stamp = match state.watermark(host, uri.path(), &params) {
+        Ok(v) => v,
+        Err(err) => {
+            tracing::error!("failed to prepare a watermark; {err}");
+            return fallback_or_message(
+                state,
+                record,
+                host,
+                uri.path(),
+                &params,
+                accepted_format,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "server error on stamping a watermark",
+                started,
+            )
+            .await;
+        }
+    };
+    // The text is drawn on the pool, and only its font is checked here.
+    if let Err(err) = state.font(&params) {
+        tracing::error!("failed to prepare a text; {err}");
+        return fallback_or_message(
+            state,
+            record,
+            host,
+            uri.path(),
+            &params,
+            accepted_format,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "server error on drawing a text",
+            started,
+        )
+        .await;
+    }
     let mut timer = simple_server_timing_header::Timer::new();
-    let accepted_format = extract_accepted_image_formats(&headers);
     // https://docs.rs/axum/latest/axum/response/index.html
     let path = uri.path();
-    let original = match state.get_image(path).await {
-        Ok(option) => match option {
-            Some(img) => {
-                timer.add("f_fetch");
-                img
+    let fetch_started = std::time::Instant::now();
+    // Box<dyn Error> is not Send, so the result must be dropped before the next await.
+    let original = {
+        let fetching = async {
+            let clean = stamp.is_none() && !params.describes();
+            if params.as_is() && clean && state.streamable(host, path) {
+                state.stream_image(host, path).await
+            } else {
+                let fetching = state.get_image(host, path).await;
+                fetching.map(|v| v.map(handler::Fetched::Buffered))
             }
-            None => {
-                let status_code = if state.treat_as_success_even_no_content(path) {
-                    StatusCode::OK
-                } else {
-                    StatusCode::NOT_FOUND
-                };
+        };
+        // https://docs.rs/tokio/latest/tokio/time/fn.timeout.html
+        let budget = state.fetch_budget(started.elapsed());
+        let fetched = tokio::time::timeout(budget, fetching)
+            .await
+            .map(|v| v.map_err(|e| e.to_string()));
+        let fetched = match fetched {
+            Ok(v) => v,
+            Err(_) => {
+                tracing::warn!("timed out fetching an original image; {path} {budget:?}");
+                return fallback_or_message(
+                    state,
+                    record,
+                    host,
+                    path,
+                    &params,
+                    accepted_format,
+                    StatusCode::GATEWAY_TIMEOUT,
+                    "timed out fetching an image",
+                    started,
+                )
+                .await;
+            }
+        };
+        match fetched {
+            Ok(option) => match option {
+                Some(handler::Fetched::Passthrough {
+                    mime_type,
+                    body,
+                    source,
+                    location,
+                }) => {
+                    timer.add("f_fetch");
+                    record.location = Some(location);
+                    let elapsed = fetch_started.elapsed();
+                    state.metrics().observe_fetch(provider, elapsed, None);
+                    let mut headers = create_header(mime_type, &params, Some(timer));
+                    append_source_timing(&mut headers, source);
+                    let left = state.request_timeout().saturating_sub(started.elapsed());
+                    let deadline = tokio::time::Instant::now() + left;
+                    return (StatusCode::OK, headers, relay_within(body, deadline));
+                }
+                Some(handler::Fetched::Buffered(img)) => {
+                    timer.add("f_fetch");
+                    let elapsed = fetch_started.elapsed();
+                    let size = Some(img.data.len());
+                    state.metrics().observe_fetch(provider, elapsed, size);
+                    record.location = Some(img.location.clone());
+                    record.cache = img.cache.map(|v| v.as_str());
+                    record.input_bytes = size;
+                    record.input_dimensions = img.dimensions();
+                    img
+                }
+                None => {
+                    let status_code = if state.treat_as_success_even_no_content(host, path) {
+                        StatusCode::OK
+                    } else {
+                        StatusCode::NOT_FOUND
+                    };
+                    return fallback_or_message(
+                        state,
+                        record,
+                        host,
+                        path,
+                        &params,
+                        accepted_format,
+                        status_code,
+                        "not found",
+                        started,
+                    )
+                    .await;
+                }
+            },
+            Err(err) => {
+                tracing::error!("failled to get an original image; {path} {err}");
                 return fallback_or_message(
-                    &state,
+                    state,
+                    record,
+                    host,
                     path,
                     &params,
                     accepted_format,
-                    status_code,
-                    "not found",
-                );
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "server error on fetching an image",
+                    started,
+                )
+                .await;
             }
-        },
-        Err(err) => {
-            tracing::error!("failled to get an original image; {path} {err:?}");
+        }
+    };
+    if params.info() {
+        let inspecting = state.inspect_image_in_pool(original.data);
+        return describe(state, inspecting, original.source, &params, started, path).await;
+    }
+    if params.lqip() {
+        let making = state.make_placeholder_in_pool(original.data);
+        return describe(state, making, original.source, &params, started, path).await;
+    }
+    // https://docs.rs/axum/latest/axum/body/struct.Body.html
+    // https://github.com/tokio-rs/axum/blob/main/examples/stream-to-file/src/main.rs
+    let process_started = std::time::Instant::now();
+    let processing =
+        state.process_image_in_pool(original.data, params.clone(), accepted_format, stamp);
+    let budget = state.process_budget(started.elapsed());
+    let processing = match tokio::time::timeout(budget, processing).await {
+        Ok(v) => v,
+        Err(_) => {
+            tracing::warn!("timed out processing an image; {path} {budget:?}");
             return fallback_or_message(
-                &state,
+                state,
+                record,
+                host,
                 path,
                 &params,
                 accepted_format,
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "server error on fetching an image",
-            );
+                StatusCode::SERVICE_UNAVAILABLE,
+                "timed out processing an image",
+                started,
+            )
+            .await;
         }
     };
-    // https://docs.rs/axum/latest/axum/body/struct.Body.html
-    // https://github.com/tokio-rs/axum/blob/main/examples/stream-to-file/src/main.rs
-    match state.process_image(&original, &params, accepted_format) {
-        Ok((mime_type, processed)) => {
+    match processing {
+        Ok(Ok((mime_type, processed))) => {
             timer.add("f_process");
-            let headers = create_header(mime_type, &params, Some(timer));
+            let elapsed = process_started.elapsed();
+            state.metrics().observe_process(provider, elapsed);
+            // AVIF is not decodable here, but it is encoded only at the requested or original size.
+            record.output_dimensions = handler::dimensions(&processed)
+                .or_else(|| params.dimensions())
+                .or(record.input_dimensions);
+            let mut headers = create_header(mime_type, &params, Some(timer));
+            append_source_timing(&mut headers, original.source);
             (StatusCode::OK, headers, Body::from(processed))
         }
-        Err(err) => {
-            tracing::error!("failed to process an image; {path} {err:?}");
+        Ok(Err(err)) => {
+            tracing::error!("failed to process an image; {path} {err}");
             fallback_or_message(
-                &state,
+                state,
+                record,
+                host,
                 path,
                 &params,
                 accepted_format,
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "server error on processing an image",
+                started,
+            )
+            .await
+        }
+        Err(rejected) => {
+            tracing::warn!("shed an image processing; {path} {rejected:?}");
+            // A fallback image would need the pool as well, which is full.
+            let headers = create_header(CONTENT_TYPE_TEXT_PLAIN, &params, None);
+            let message = "server is too busy to process an image";
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                headers,
+                Body::from(message),
             )
         }
     }
 }
 
+// Answers what is computed of an original as JSON, such as its properties or a placeholder.
+async fn describe<T: serde::Serialize>(
+    state: &handler::State,
+    working: impl std::future::Future<Output = Result<Result<T, String>, pool::Rejected>>,
+    source: usize,
+    params: &query::Query,
+    started: std::time::Instant,
+    path: &str,
+) -> (StatusCode, header::HeaderMap, Body) {
+    let budget = state.process_budget(started.elapsed());
+    let (status, message) = match tokio::time::timeout(budget, working).await {
+        Ok(Ok(Ok(v))) => match serde_json::to_vec(&v) {
+            Ok(json) => {
+                let mut headers = create_header(CONTENT_TYPE_JSON, params, None);
+                append_source_timing(&mut headers, source);
+                return (StatusCode::OK, headers, Body::from(json));
+            }
+            Err(err) => {
+                tracing::error!("failed to serialize a description; {path} {err:?}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "server error on describing an image",
+                )
+            }
+        },
+        Ok(Ok(Err(err))) => {
+            tracing::warn!("failed to describe an image; {path} {err}");
+            (StatusCode::UNPROCESSABLE_ENTITY, "unsupported image")
+        }
+        Ok(Err(rejected)) => {
+            tracing::warn!("shed an image description; {path} {rejected:?}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server is too busy to describe an image",
+            )
+        }
+        Err(_) => {
+            tracing::warn!("timed out describing an image; {path} {budget:?}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "timed out describing an image",
+            )
+        }
+    };
+    let headers = create_header(CONTENT_TYPE_TEXT_PLAIN, params, None);
+    (status, headers, Body::from(message))
+}
+
 fn create_header(
     content_type: &'static str,
     params: &query::Query,
@@ -232,20 +705,76 @@ fn try_create_header(
     Ok(headers)
 }
 
-fn fallback_or_message(
-    state: &handler::State,
+// Sources are told by their indices, so that bucket names and origins are not exposed.
+fn append_source_timing(headers: &mut header::HeaderMap, source: usize) {
+    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Server-Timing
+    let value = format!("f_src;desc=\"{source}\"");
+    match header::HeaderValue::from_str(value.as_str()) {
+        Ok(v) => {
+            headers.append(simple_server_timing_header::Timer::header_key(), v);
+        }
+        Err(err) => tracing::error!("failed to create header; {err:?}"),
+    }
+}
+
+// A relayed body is cut off with an error once the request budget runs out,
+// since its status and headers are already sent.
+fn relay_within(body: Body, deadline: tokio::time::Instant) -> Body {
+    use futures_util::StreamExt;
+    // https://docs.rs/futures-util/latest/futures_util/stream/fn.unfold.html
+    let chunks =
+        futures_util::stream::unfold(Some(body.into_data_stream()), move |stream| async move {
+            let mut stream = stream?;
+            match tokio::time::timeout_at(deadline, stream.next()).await {
+                Ok(Some(chunk)) => Some((chunk, Some(stream))),
+                Ok(None) => None,
+                Err(_) => {
+                    tracing::warn!("timed out relaying an image");
+                    let err = axum::Error::new("timed out relaying an image");
+                    Some((Err(err), None))
+                }
+            }
+        });
+    Body::from_stream(chunks)
+}
+
+// A fallback image is processed on the pool within what is left of the process budget,
+// and the message is answered instead if it cannot be.
+#[allow(clippy::too_many_arguments)]
+async fn fallback_or_message(
+    state: &std::sync::Arc<handler::State>,
+    record: &mut access_log::Record,
+    host: Option<&str>,
     req_path: &str,
     params: &query::Query,
     content: content::Format,
     status: StatusCode,
     message: &'static str,
+    started: std::time::Instant,
 ) -> (StatusCode, header::HeaderMap, Body) {
-    match state.fallback(req_path, params, content) {
+    // Descriptions are asked by programs, which would be misled by those of a fallback image.
+    if params.describes() {
+        let headers = create_header(CONTENT_TYPE_TEXT_PLAIN, params, None);
+        return (status, headers, Body::from(message));
+    }
+    let budget = state.process_budget(started.elapsed());
+    let processing = state.fallback_in_pool(host, req_path, params.clone(), content);
+    let processed = match tokio::time::timeout(budget, processing).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(rejected)) => Err(format!("{rejected:?}")),
+        Err(_) => Err(format!("timed out in {budget:?}")),
+    };
+    match processed {
         Ok((mime_type, processed)) => {
+            let provider = state.provider_label(host, req_path);
+            state.metrics().count_fallback(provider);
+            record.fallback = Some(message);
+            record.output_dimensions = handler::dimensions(&processed);
             let headers = create_header(mime_type, params, None);
             (status, headers, Body::from(processed))
         }
-        Err(_err) => {
+        Err(err) => {
+            tracing::debug!("failed to serve a fallback image; {req_path} {err}");
             let headers = create_header(CONTENT_TYPE_TEXT_PLAIN, params, None);
             (status, headers, Body::from(message))
         }
@@ -301,7 +830,18 @@ mod tests {
 
     #[tokio::test]
     async fn test_generic_handler() {
-        let client = infra::Client::for_test().await;
+        let (gcs_port, gcs_mock_server) = infra::gcs::run_mock_server("local-test", "images").await;
+        let (azure_port, azure_mock_server) =
+            infra::azure::run_mock_server("local-test", "images").await;
+        let client = infra::Client::for_test()
+            .await
+            .with_s3_client("mirror", infra::s3::Client::for_test().await)
+            .with_gcs_client(infra::gcs::Client::for_test(format!(
+                "http://127.0.0.1:{gcs_port}"
+            )))
+            .with_azure_client(infra::azure::Client::for_test(format!(
+                "http://127.0.0.1:{azure_port}/devstoreaccount1"
+            )));
         let mut bucket_manager = infra::s3::BucketManager::new(client.s3.clone());
         let bucket = bucket_manager
             .create()
@@ -315,27 +855,82 @@ mod tests {
         let providers = Vec::from([
             config::Provider {
                 path: "foo".to_string(),
-                src: format!("s3://{bucket}/images"),
-                fallback_path: None,
-                success_even_no_content: None,
+                src: format!("s3://{bucket}/images").into(),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "foo".to_string(),
+                hosts: Some(Vec::from([
+                    "brand.example.com".to_string(),
+                    "*.brand.example.net".to_string(),
+                ])),
+                src: "file://localhost/./nothing".to_string().into(),
+                ..Default::default()
             },
             config::Provider {
                 path: "bar".to_string(),
-                src: format!("http://127.0.0.1:{port}/images"),
-                fallback_path: None,
-                success_even_no_content: None,
+                src: format!("http://127.0.0.1:{port}/images").into(),
+                ..Default::default()
             },
             config::Provider {
                 path: "baz".to_string(),
-                src: "file://localhost/./images".to_string(),
-                fallback_path: None,
-                success_even_no_content: None,
+                src: "file://localhost/./images".to_string().into(),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "qux".to_string(),
+                src: format!("s3://{bucket}/images").into(),
+                s3_client: Some("mirror".to_string()),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "gcs".to_string(),
+                src: "gs://local-test/images".to_string().into(),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "azure".to_string(),
+                src: "az://local-test/images".to_string().into(),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "img".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                rewrites: Some(Vec::from([
+                    config::rewrite::Rule {
+                        pattern: r"^(?P<name>\w+)/thumb\.(?P<ext>\w+)$".to_string(),
+                        replacement: "$name.jpg".to_string(),
+                        query: Some("w=150&h=150&$ext=true".to_string()),
+                    },
+                    config::rewrite::Rule {
+                        pattern: r"^(?P<name>\w+)/original\.(?P<ext>\w+)$".to_string(),
+                        replacement: "$name.$ext".to_string(),
+                        query: None,
+                    },
+                ])),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "chain".to_string(),
+                src: config::Src::Many(Vec::from([
+                    format!("s3://{bucket}/nothing"),
+                    format!("http://127.0.0.1:{port}/nothing"),
+                    "file://localhost/./images".to_string(),
+                ])),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "broken".to_string(),
+                src: config::Src::Many(Vec::from([
+                    "http://127.0.0.1:1/images".to_string(),
+                    "file://localhost/./images".to_string(),
+                ])),
+                ..Default::default()
             },
             config::Provider {
                 path: "/".to_string(),
-                src: "file://localhost/./images".to_string(),
-                fallback_path: None,
-                success_even_no_content: None,
+                src: "file://localhost/./images".to_string().into(),
+                ..Default::default()
             },
         ]);
         let state = std::sync::Arc::new(handler::State::new(providers, client));
@@ -390,6 +985,21 @@ mod tests {
                 want_status: StatusCode::OK,
                 want_type: "image/svg+xml",
             },
+            Case {
+                url: "http://127.0.0.1:3000/foo/logo.svg?w=300&h=200",
+                want_status: StatusCode::OK,
+                want_type: "image/png",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/foo/logo.svg?w=300&h=200&crop=true&webp=true",
+                want_status: StatusCode::OK,
+                want_type: "image/webp",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/foo/logo.svg?grayscale=true",
+                want_status: StatusCode::OK,
+                want_type: "image/png",
+            },
             Case {
                 url: "http://127.0.0.1:3000/foo/lenna.gif?w=300&h=200&webp=true",
                 want_status: StatusCode::OK,
@@ -405,6 +1015,26 @@ mod tests {
                 want_status: StatusCode::NOT_FOUND,
                 want_type: "text/plain; charset=utf-8",
             },
+            Case {
+                url: "http://brand.example.com/foo/lenna.jpg",
+                want_status: StatusCode::NOT_FOUND,
+                want_type: "text/plain; charset=utf-8",
+            },
+            Case {
+                url: "http://cdn.brand.example.net:3000/foo/lenna.jpg",
+                want_status: StatusCode::NOT_FOUND,
+                want_type: "text/plain; charset=utf-8",
+            },
+            Case {
+                url: "http://brand.example.net/foo/lenna.jpg",
+                want_status: StatusCode::OK,
+                want_type: "image/jpeg",
+            },
+            Case {
+                url: "http://brand.example.com/bar/lenna.jpg",
+                want_status: StatusCode::OK,
+                want_type: "image/jpeg",
+            },
             Case {
                 url: "http://127.0.0.1:3000/bar/lenna.jpg",
                 want_status: StatusCode::OK,
@@ -425,6 +1055,71 @@ mod tests {
                 want_status: StatusCode::NOT_FOUND,
                 want_type: "text/plain; charset=utf-8",
             },
+            Case {
+                url: "http://127.0.0.1:3000/qux/lenna.jpg",
+                want_status: StatusCode::OK,
+                want_type: "image/jpeg",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/gcs/lenna.jpg",
+                want_status: StatusCode::OK,
+                want_type: "image/jpeg",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/gcs/who.jpg",
+                want_status: StatusCode::NOT_FOUND,
+                want_type: "text/plain; charset=utf-8",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/azure/lenna.jpg",
+                want_status: StatusCode::OK,
+                want_type: "image/jpeg",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/azure/who.jpg",
+                want_status: StatusCode::NOT_FOUND,
+                want_type: "text/plain; charset=utf-8",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/img/lenna/original.jpg",
+                want_status: StatusCode::OK,
+                want_type: "image/jpeg",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/img/lenna/thumb.webp",
+                want_status: StatusCode::OK,
+                want_type: "image/webp",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/img/lenna/thumb.webp?w=9999",
+                want_status: StatusCode::BAD_REQUEST,
+                want_type: "text/plain; charset=utf-8",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/img/lenna.jpg",
+                want_status: StatusCode::OK,
+                want_type: "image/jpeg",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/chain/lenna.jpg",
+                want_status: StatusCode::OK,
+                want_type: "image/jpeg",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/chain/who.jpg",
+                want_status: StatusCode::NOT_FOUND,
+                want_type: "text/plain; charset=utf-8",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/broken/lenna.jpg",
+                want_status: StatusCode::OK,
+                want_type: "image/jpeg",
+            },
+            Case {
+                url: "http://127.0.0.1:3000/broken/who.jpg",
+                want_status: StatusCode::NOT_FOUND,
+                want_type: "text/plain; charset=utf-8",
+            },
             Case {
                 url: "http://127.0.0.1:3000/lenna.jpg",
                 want_status: StatusCode::OK,
@@ -451,9 +1146,15 @@ mod tests {
                     header::HeaderValue::from_str(image::ImageFormat::Avif.to_mime_type()).unwrap(),
                 )
                 .unwrap();
-            let got = generic_handler(headers, OriginalUri(uri), query, State(state.clone()))
-                .await
-                .into_response();
+            let got = generic_handler(
+                ConnectInfo(std::net::SocketAddr::from(([127, 0, 0, 1], 50000))),
+                headers,
+                OriginalUri(uri),
+                query,
+                State(state.clone()),
+            )
+            .await
+            .into_response();
             assert_eq!(
                 got.status(),
                 c.want_status,
@@ -466,9 +1167,901 @@ mod tests {
                 "case: {}, bucket: {bucket}",
                 c.url
             );
+            if c.url.ends_with("/broken/lenna.jpg") {
+                let timing = got.headers().get_all("server-timing");
+                assert!(timing.iter().any(|v| v == "f_src;desc=\"1\""));
+            }
         }
         bucket_manager.clean().await.unwrap();
         mock_server.abort();
+        gcs_mock_server.abort();
+        azure_mock_server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_generic_handler_with_timeouts() {
+        let router = axum::Router::new().route(
+            "/images/{*p}",
+            get(|| async {
+                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+                StatusCode::NOT_FOUND
+            }),
+        );
+        let addr = std::net::SocketAddr::from((std::net::Ipv4Addr::new(127, 0, 0, 1), 0));
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let slow_server = tokio::spawn(async move {
+            axum::serve(listener, router).await.unwrap();
+        });
+        let providers = Vec::from([
+            config::Provider {
+                path: "slow".to_string(),
+                src: format!("http://127.0.0.1:{port}/images").into(),
+                fallback_path: Some("/local/lenna.jpg".to_string()),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "local".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                fallback_path: Some("/local/lenna.jpg".to_string()),
+                ..Default::default()
+            },
+        ]);
+        let client = infra::Client::for_test().await;
+        let mut state = handler::State::new(providers.clone(), client);
+        state
+            .with_fallback(&None, &providers)
+            .await
+            .expect("failed to load fallback images");
+        state.configure_timeouts(config::timeout::Config {
+            request_ms: 1000,
+            fetch_ms: Some(100),
+            process_ms: Some(0),
+        });
+        assert_eq!(
+            state.fetch_budget(std::time::Duration::from_millis(950)),
+            std::time::Duration::from_millis(50)
+        );
+        assert_eq!(
+            state.process_budget(std::time::Duration::from_secs(2)),
+            std::time::Duration::ZERO
+        );
+        let state = std::sync::Arc::new(state);
+        // Jobs always wait longer than the queue timeout, so processing is shed.
+        let mut shedding = handler::State::new(providers.clone(), infra::Client::for_test().await);
+        shedding
+            .with_fallback(&None, &providers)
+            .await
+            .expect("failed to load fallback images");
+        shedding.configure_processing_pool(config::processing::Config {
+            threads: 1,
+            queue_depth: std::num::NonZeroUsize::MIN,
+            queue_timeout_ms: 0,
+        });
+        let shedding = std::sync::Arc::new(shedding);
+        // Fallback images are processed within the process budget, which is left here.
+        let mut patient = handler::State::new(providers.clone(), infra::Client::for_test().await);
+        patient
+            .with_fallback(&None, &providers)
+            .await
+            .expect("failed to load fallback images");
+        patient.configure_timeouts(config::timeout::Config {
+            request_ms: 10000,
+            fetch_ms: Some(100),
+            process_ms: None,
+        });
+        let patient = std::sync::Arc::new(patient);
+        for (state, url, want_status, want_type) in [
+            (
+                &patient,
+                "http://127.0.0.1:3000/slow/lenna.jpg",
+                StatusCode::GATEWAY_TIMEOUT,
+                "image/jpeg",
+            ),
+            // The fallback image cannot be resized within the budget either.
+            (
+                &state,
+                "http://127.0.0.1:3000/local/lenna.jpg?w=300&h=200",
+                StatusCode::SERVICE_UNAVAILABLE,
+                "text/plain; charset=utf-8",
+            ),
+            (
+                &shedding,
+                "http://127.0.0.1:3000/local/lenna.jpg?w=300&h=200",
+                StatusCode::SERVICE_UNAVAILABLE,
+                "text/plain; charset=utf-8",
+            ),
+        ] {
+            let uri = url.parse::<axum::http::Uri>().unwrap();
+            let query: Query<query::Query> = axum::extract::Query::try_from_uri(&uri).unwrap();
+            let got = generic_handler(
+                ConnectInfo(std::net::SocketAddr::from(([127, 0, 0, 1], 50000))),
+                header::HeaderMap::new(),
+                OriginalUri(uri),
+                query,
+                State(state.clone()),
+            )
+            .await
+            .into_response();
+            assert_eq!(got.status(), want_status, "case: {url}");
+            assert_eq!(
+                got.headers().get(header::CONTENT_TYPE).unwrap(),
+                want_type,
+                "case: {url}"
+            );
+        }
+        slow_server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_generic_handler_with_hotlink_protection() {
+        let hotlink = |use_fallback| {
+            Some(config::hotlink::Config {
+                allowed_referers: Vec::from(["example.com".to_string()]),
+                allow_empty_referer: None,
+                use_fallback: Some(use_fallback),
+            })
+        };
+        let providers = Vec::from([
+            config::Provider {
+                path: "strict".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                hotlink: hotlink(false),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "placeholder".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                fallback_path: Some("/placeholder/lenna.jpg".to_string()),
+                hotlink: hotlink(true),
+                ..Default::default()
+            },
+        ]);
+        let client = infra::Client::for_test().await;
+        let mut state = handler::State::new(providers.clone(), client);
+        state
+            .with_fallback(&None, &providers)
+            .await
+            .expect("failed to load fallback images");
+        let state = std::sync::Arc::new(state);
+        for (url, referer, want_status, want_type) in [
+            (
+                "http://127.0.0.1:3000/strict/lenna.jpg",
+                "https://example.com/",
+                StatusCode::OK,
+                "image/jpeg",
+            ),
+            (
+                "http://127.0.0.1:3000/strict/lenna.jpg",
+                "https://evil.test/",
+                StatusCode::FORBIDDEN,
+                "text/plain; charset=utf-8",
+            ),
+            (
+                "http://127.0.0.1:3000/placeholder/lenna.png",
+                "https://evil.test/",
+                StatusCode::FORBIDDEN,
+                "image/jpeg",
+            ),
+        ] {
+            let uri = url.parse::<axum::http::Uri>().unwrap();
+            let query: Query<query::Query> = axum::extract::Query::try_from_uri(&uri).unwrap();
+            let mut headers = header::HeaderMap::new();
+            headers.insert(header::REFERER, header::HeaderValue::from_static(referer));
+            let got = generic_handler(
+                ConnectInfo(std::net::SocketAddr::from(([127, 0, 0, 1], 50000))),
+                headers,
+                OriginalUri(uri),
+                query,
+                State(state.clone()),
+            )
+            .await
+            .into_response();
+            assert_eq!(got.status(), want_status, "case: {url} {referer}");
+            assert_eq!(
+                got.headers().get(header::CONTENT_TYPE).unwrap(),
+                want_type,
+                "case: {url} {referer}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generic_handler_with_rate_limit() {
+        let providers = Vec::from([
+            config::Provider {
+                path: "strict".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                rate_limit: Some(config::rate_limit::Config {
+                    per_second: 0.001,
+                    burst: 1.0,
+                    costs: None,
+                }),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "local".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                ..Default::default()
+            },
+        ]);
+        let client = infra::Client::for_test().await;
+        let mut state = handler::State::new(providers, client);
+        state.enable_rate_limit(config::rate_limit::Config {
+            per_second: 0.001,
+            burst: 4.0,
+            costs: Some(config::rate_limit::Costs {
+                avif: Some(3.0),
+                ..Default::default()
+            }),
+        });
+        state.trust_proxies(&["127.0.0.1".to_string()]);
+        let state = std::sync::Arc::new(state);
+        for (url, forwarded, want_status) in [
+            (
+                "http://127.0.0.1:3000/strict/lenna.jpg",
+                None,
+                StatusCode::OK,
+            ),
+            (
+                "http://127.0.0.1:3000/strict/lenna.jpg",
+                None,
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                "http://127.0.0.1:3000/local/lenna.jpg",
+                None,
+                StatusCode::OK,
+            ),
+            (
+                "http://127.0.0.1:3000/local/lenna.jpg?w=300&h=200&avif=true",
+                None,
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                "http://127.0.0.1:3000/local/lenna.jpg",
+                None,
+                StatusCode::OK,
+            ),
+            // The global token taken by the request refused by "strict" was given back.
+            (
+                "http://127.0.0.1:3000/local/lenna.jpg",
+                None,
+                StatusCode::OK,
+            ),
+            (
+                "http://127.0.0.1:3000/local/lenna.jpg",
+                None,
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                "http://127.0.0.1:3000/local/lenna.jpg",
+                Some("198.51.100.1"),
+                StatusCode::OK,
+            ),
+        ] {
+            let uri = url.parse::<axum::http::Uri>().unwrap();
+            let query: Query<query::Query> = axum::extract::Query::try_from_uri(&uri).unwrap();
+            let mut headers = header::HeaderMap::new();
+            headers.insert(
+                header::ACCEPT,
+                header::HeaderValue::from_static("image/avif"),
+            );
+            if let Some(v) = forwarded {
+                headers.insert("x-forwarded-for", header::HeaderValue::from_static(v));
+            }
+            let got = generic_handler(
+                ConnectInfo(std::net::SocketAddr::from(([127, 0, 0, 1], 50000))),
+                headers,
+                OriginalUri(uri),
+                query,
+                State(state.clone()),
+            )
+            .await
+            .into_response();
+            assert_eq!(got.status(), want_status, "case: {url} {forwarded:?}");
+            if want_status == StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = got.headers().get(header::RETRY_AFTER).unwrap();
+                assert!(retry_after.to_str().unwrap().parse::<u64>().unwrap() >= 1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generic_handler_with_descriptions() {
+        let providers = Vec::from([config::Provider {
+            path: "images".to_string(),
+            src: "file://localhost/./images".to_string().into(),
+            fallback_path: Some("/images/lenna.jpg".to_string()),
+            ..Default::default()
+        }]);
+        let client = infra::Client::for_test().await;
+        let mut state = handler::State::new(providers.clone(), client);
+        state
+            .with_fallback(&None, &providers)
+            .await
+            .expect("failed to load fallback images");
+        let state = std::sync::Arc::new(state);
+        for (url, want_status, want_type, want_format) in [
+            (
+                "http://127.0.0.1:3000/_info/images/lenna.png",
+                StatusCode::OK,
+                "application/json",
+                Some("image/png"),
+            ),
+            (
+                "http://127.0.0.1:3000/images/lenna.gif?info=true&w=300&h=200",
+                StatusCode::OK,
+                "application/json",
+                Some("image/gif"),
+            ),
+            (
+                "http://127.0.0.1:3000/images/lenna.jpg?lqip=true",
+                StatusCode::OK,
+                "application/json",
+                None,
+            ),
+            (
+                "http://127.0.0.1:3000/_info/images/who.jpg",
+                StatusCode::NOT_FOUND,
+                "text/plain; charset=utf-8",
+                None,
+            ),
+            (
+                "http://127.0.0.1:3000/images/lenna.txt?info=true",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "text/plain; charset=utf-8",
+                None,
+            ),
+        ] {
+            let uri = url.parse::<axum::http::Uri>().unwrap();
+            let query: Query<query::Query> = axum::extract::Query::try_from_uri(&uri).unwrap();
+            let got = generic_handler(
+                ConnectInfo(std::net::SocketAddr::from(([127, 0, 0, 1], 50000))),
+                header::HeaderMap::new(),
+                OriginalUri(uri),
+                query,
+                State(state.clone()),
+            )
+            .await
+            .into_response();
+            assert_eq!(got.status(), want_status, "case: {url}");
+            assert_eq!(
+                got.headers().get(header::CONTENT_TYPE).unwrap(),
+                want_type,
+                "case: {url}"
+            );
+            let body = axum::body::to_bytes(got.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let info = serde_json::from_slice::<serde_json::Value>(&body).ok();
+            assert_eq!(
+                info.as_ref().and_then(|v| v["format"].as_str()),
+                want_format,
+                "case: {url}"
+            );
+            if let Some(info) = info {
+                assert_eq!(info["width"], 512, "case: {url}");
+                assert_eq!(info["height"], 512, "case: {url}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generic_handler_with_watermark() {
+        let watermark = |enforced| {
+            Some(config::watermark::Config {
+                path: "/logo/lenna.png".to_string(),
+                position: None,
+                margin: Some(8),
+                opacity: Some(50),
+                scale: Some(10),
+                enforced: Some(enforced),
+            })
+        };
+        let providers = Vec::from([
+            config::Provider {
+                path: "logo".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "enforced".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                watermark: watermark(true),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "optional".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                watermark: watermark(false),
+                ..Default::default()
+            },
+        ]);
+        let client = infra::Client::for_test().await;
+        let unloaded = std::sync::Arc::new(handler::State::new(providers.clone(), client.clone()));
+        let mut state = handler::State::new(providers.clone(), client);
+        state
+            .with_watermarks(&providers)
+            .await
+            .expect("failed to load watermark images");
+        let state = std::sync::Arc::new(state);
+        for (state, url, want_status, want_type, want_clean) in [
+            (
+                &state,
+                "/enforced/lenna.jpg",
+                StatusCode::OK,
+                "image/jpeg",
+                false,
+            ),
+            (
+                &state,
+                "/enforced/lenna.jpg?wm=false",
+                StatusCode::OK,
+                "image/jpeg",
+                false,
+            ),
+            (
+                &state,
+                "/enforced/lenna.gif",
+                StatusCode::OK,
+                "image/gif",
+                false,
+            ),
+            (
+                &state,
+                "/enforced/logo.svg",
+                StatusCode::OK,
+                "image/png",
+                false,
+            ),
+            (
+                &state,
+                "/optional/lenna.jpg",
+                StatusCode::OK,
+                "image/jpeg",
+                true,
+            ),
+            (
+                &state,
+                "/optional/logo.svg",
+                StatusCode::OK,
+                "image/svg+xml",
+                true,
+            ),
+            (
+                &state,
+                "/optional/lenna.png?wm=true&wm_pos=top-left",
+                StatusCode::OK,
+                "image/png",
+                false,
+            ),
+            (
+                &unloaded,
+                "/enforced/lenna.jpg",
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "text/plain; charset=utf-8",
+                false,
+            ),
+            (
+                &unloaded,
+                "/optional/lenna.jpg",
+                StatusCode::OK,
+                "image/jpeg",
+                true,
+            ),
+        ] {
+            let uri = format!("http://127.0.0.1:3000{url}")
+                .parse::<axum::http::Uri>()
+                .unwrap();
+            let query: Query<query::Query> = axum::extract::Query::try_from_uri(&uri).unwrap();
+            let got = generic_handler(
+                ConnectInfo(std::net::SocketAddr::from(([127, 0, 0, 1], 50000))),
+                header::HeaderMap::new(),
+                OriginalUri(uri.clone()),
+                query,
+                State(state.clone()),
+            )
+            .await
+            .into_response();
+            assert_eq!(got.status(), want_status, "case: {url}");
+            assert_eq!(
+                got.headers().get(header::CONTENT_TYPE).unwrap(),
+                want_type,
+                "case: {url}"
+            );
+            let body = axum::body::to_bytes(got.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let name = uri.path().rsplit('/').next().unwrap();
+            let original = std::fs::read(format!("images/{name}")).unwrap();
+            assert_eq!(body == original, want_clean, "case: {url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generic_handler_with_text() {
+        let providers = Vec::from([config::Provider {
+            path: "images".to_string(),
+            src: "file://localhost/./images".to_string().into(),
+            ..Default::default()
+        }]);
+        let client = infra::Client::for_test().await;
+        let unloaded = std::sync::Arc::new(handler::State::new(providers.clone(), client.clone()));
+        let mut state = handler::State::new(providers, client);
+        state
+            .load_fonts(&config::text::Config {
+                font_dir: "fonts".to_string(),
+                default_font: None,
+            })
+            .expect("failed to load fonts");
+        let state = std::sync::Arc::new(state);
+        for (state, url, want_status, want_type, want_clean) in [
+            (
+                &state,
+                "/images/lenna.jpg?text=SOLD&text_bg=0,0,0,128",
+                StatusCode::OK,
+                "image/jpeg",
+                false,
+            ),
+            (
+                &state,
+                "/images/lenna.gif?text=SOLD&font=DejaVuSans&text_pos=center",
+                StatusCode::OK,
+                "image/gif",
+                false,
+            ),
+            (
+                &state,
+                "/images/lenna.png?text=",
+                StatusCode::OK,
+                "image/png",
+                true,
+            ),
+            (
+                &state,
+                "/images/lenna.jpg?text=SOLD&font=Unknown",
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "text/plain; charset=utf-8",
+                false,
+            ),
+            (
+                &unloaded,
+                "/images/lenna.jpg?text=SOLD",
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "text/plain; charset=utf-8",
+                false,
+            ),
+        ] {
+            let uri = format!("http://127.0.0.1:3000{url}")
+                .parse::<axum::http::Uri>()
+                .unwrap();
+            let query: Query<query::Query> = axum::extract::Query::try_from_uri(&uri).unwrap();
+            let got = generic_handler(
+                ConnectInfo(std::net::SocketAddr::from(([127, 0, 0, 1], 50000))),
+                header::HeaderMap::new(),
+                OriginalUri(uri.clone()),
+                query,
+                State(state.clone()),
+            )
+            .await
+            .into_response();
+            assert_eq!(got.status(), want_status, "case: {url}");
+            assert_eq!(
+                got.headers().get(header::CONTENT_TYPE).unwrap(),
+                want_type,
+                "case: {url}"
+            );
+            let body = axum::body::to_bytes(got.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let name = uri.path().rsplit('/').next().unwrap();
+            let original = std::fs::read(format!("images/{name}")).unwrap();
+            assert_eq!(body == original, want_clean, "case: {url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log_record() {
+        let providers = Vec::from([config::Provider {
+            path: "images".to_string(),
+            src: "file://localhost/./images".to_string().into(),
+            fallback_path: Some("/images/lenna.jpg".to_string()),
+            ..Default::default()
+        }]);
+        let client = infra::Client::for_test().await;
+        let mut state = handler::State::new(providers.clone(), client);
+        state
+            .with_fallback(&None, &providers)
+            .await
+            .expect("failed to load fallback images");
+        let state = std::sync::Arc::new(state);
+        let png_size = std::fs::metadata("images/lenna.png").unwrap().len() as usize;
+        let cases = [
+            (
+                "http://127.0.0.1:3000/images/lenna.png?webp=true&w=300&h=200&foo=bar",
+                access_log::Record {
+                    provider: Some("images".to_string()),
+                    location: Some("images/lenna.png".to_string()),
+                    query: Some("w=300&h=200&webp=true".to_string()),
+                    input_bytes: Some(png_size),
+                    input_dimensions: Some((512, 512)),
+                    output_dimensions: Some((300, 200)),
+                    fallback: None,
+                    cache: None,
+                },
+            ),
+            (
+                "http://127.0.0.1:3000/images/who.jpg?w=100&h=100",
+                access_log::Record {
+                    provider: Some("images".to_string()),
+                    location: None,
+                    query: Some("w=100&h=100".to_string()),
+                    input_bytes: None,
+                    input_dimensions: None,
+                    output_dimensions: Some((100, 100)),
+                    fallback: Some("not found"),
+                    cache: None,
+                },
+            ),
+        ];
+        for (url, want) in cases {
+            let uri = url.parse::<axum::http::Uri>().unwrap();
+            let query: Query<query::Query> = axum::extract::Query::try_from_uri(&uri).unwrap();
+            let got = generic_handler(
+                ConnectInfo(std::net::SocketAddr::from(([127, 0, 0, 1], 50000))),
+                header::HeaderMap::new(),
+                OriginalUri(uri),
+                query,
+                State(state.clone()),
+            )
+            .await
+            .into_response();
+            let record = got.extensions().get::<access_log::Record>();
+            assert_eq!(record, Some(&want), "case: {url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let providers = Vec::from([
+            config::Provider {
+                path: "editor".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                cors: Some(config::cors::Config {
+                    allowed_origins: Vec::from(["https://editor.example.com".to_string()]),
+                    allowed_methods: None,
+                    allowed_headers: None,
+                    exposed_headers: None,
+                    max_age_seconds: Some(600),
+                    allow_credentials: Some(true),
+                }),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "local".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                ..Default::default()
+            },
+        ]);
+        let client = infra::Client::for_test().await;
+        let mut state = handler::State::new(providers, client);
+        state.enable_cors(config::cors::Config {
+            allowed_origins: Vec::from(["*".to_string()]),
+            allowed_methods: None,
+            allowed_headers: None,
+            exposed_headers: None,
+            max_age_seconds: None,
+            allow_credentials: None,
+        });
+        let state = std::sync::Arc::new(state);
+        let router = axum::Router::new()
+            .fallback(generic_handler)
+            .layer(axum::middleware::from_fn_with_state(state.clone(), cors))
+            .with_state(state);
+        let addr = std::net::SocketAddr::from((std::net::Ipv4Addr::new(127, 0, 0, 1), 0));
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+        let http = reqwest::Client::new();
+        let base = format!("http://127.0.0.1:{port}");
+
+        let got = http
+            .request(reqwest::Method::OPTIONS, format!("{base}/editor/lenna.jpg"))
+            .header("origin", "https://editor.example.com")
+            .header("access-control-request-method", "GET")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(got.status(), reqwest::StatusCode::NO_CONTENT);
+        assert_eq!(
+            got.headers()["access-control-allow-origin"],
+            "https://editor.example.com"
+        );
+        assert_eq!(got.headers()["access-control-allow-methods"], "GET, HEAD");
+        assert_eq!(got.headers()["access-control-max-age"], "600");
+
+        let got = http
+            .get(format!("{base}/editor/lenna.jpg?w=300&h=200&webp=true"))
+            .header("origin", "https://editor.example.com")
+            .header("accept", "image/webp")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(got.status(), reqwest::StatusCode::OK);
+        assert_eq!(got.headers()["content-type"], "image/webp");
+        assert_eq!(got.headers()["vary"], "Accept, Origin");
+        assert_eq!(got.headers()["access-control-allow-credentials"], "true");
+
+        let got = http
+            .get(format!("{base}/editor/lenna.jpg"))
+            .header("origin", "https://evil.test")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(got.status(), reqwest::StatusCode::OK);
+        assert!(got.headers().get("access-control-allow-origin").is_none());
+
+        let got = http
+            .get(format!("{base}/local/lenna.jpg"))
+            .header("origin", "https://evil.test")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(got.headers()["access-control-allow-origin"], "*");
+        assert_eq!(got.headers()["vary"], "Origin");
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let providers = Vec::from([config::Provider {
+            path: "local".to_string(),
+            src: "file://localhost/./images".to_string().into(),
+            fallback_path: Some("/local/lenna.jpg".to_string()),
+            ..Default::default()
+        }]);
+        let client = infra::Client::for_test().await;
+        let mut state = handler::State::new(providers.clone(), client);
+        state
+            .with_fallback(&None, &providers)
+            .await
+            .expect("failed to load fallback images");
+        state.metrics().set_max_clients(8);
+        let state = std::sync::Arc::new(state);
+        let router = axum::Router::new()
+            .route("/metrics", get(metrics_handler))
+            .fallback(generic_handler)
+            .layer(
+                tower::ServiceBuilder::new()
+                    .layer(axum::middleware::from_fn_with_state(state.clone(), track))
+                    .layer(tower::limit::concurrency::ConcurrencyLimitLayer::new(8))
+                    .layer(axum::middleware::from_fn_with_state(
+                        state.clone(),
+                        count_in_flight,
+                    )),
+            )
+            .with_state(state);
+        let addr = std::net::SocketAddr::from((std::net::Ipv4Addr::new(127, 0, 0, 1), 0));
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+        let base = format!("http://127.0.0.1:{port}");
+        for (path, want_status) in [
+            ("/local/lenna.png?w=300&h=200", 200),
+            ("/local/who.jpg", 404),
+        ] {
+            let got = reqwest::get(format!("{base}{path}")).await.unwrap();
+            assert_eq!(got.status().as_u16(), want_status, "case: {path}");
+        }
+        let got = reqwest::get(format!("{base}/metrics")).await.unwrap();
+        assert_eq!(got.headers()["content-type"], prometheus::TEXT_FORMAT);
+        let got = got.text().await.unwrap();
+        for want in [
+            "fanlin_max_clients 8",
+            "fanlin_in_flight_requests 1",
+            r#"fanlin_requests_total{format="image/png",provider="local",status="200"} 1"#,
+            r#"fanlin_requests_total{format="image/jpeg",provider="local",status="404"} 1"#,
+            r#"fanlin_fetch_seconds_count{provider="local"} 1"#,
+            r#"fanlin_process_seconds_count{provider="local"} 1"#,
+            r#"fanlin_input_bytes_count{provider="local"} 1"#,
+            "fanlin_output_bytes_count 2",
+            r#"fanlin_fallbacks_total{provider="local"} 1"#,
+        ] {
+            assert!(got.contains(want), "want: {want}, got: {got}");
+        }
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_readyz() {
+        let (port, mock_server) = infra::web::run_mock_server("/images", "images").await;
+        let providers = Vec::from([
+            config::Provider {
+                path: "local".to_string(),
+                src: "file://localhost/./images".to_string().into(),
+                fallback_path: Some("/local/lenna.jpg".to_string()),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "web".to_string(),
+                src: config::Src::Many(Vec::from([
+                    "http://127.0.0.1:1/images".to_string(),
+                    format!("http://127.0.0.1:{port}/images"),
+                ])),
+                ..Default::default()
+            },
+            config::Provider {
+                path: "missing".to_string(),
+                src: "file://localhost/./missing".to_string().into(),
+                ..Default::default()
+            },
+        ]);
+        let client = infra::Client::for_test().await;
+        let mut state = handler::State::new(providers.clone(), client);
+        state.configure_health_checks(config::health::Config {
+            probe_ttl_seconds: 60,
+            probe_timeout_ms: 1000,
+        });
+        state
+            .with_fallback(&None, &providers)
+            .await
+            .expect("failed to load fallback images");
+        state.create_cmyk_to_rgb_converter("missing.icc").await;
+        let got = readyz_handler(State(std::sync::Arc::new(state)))
+            .await
+            .into_response();
+        assert_eq!(got.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = axum::body::to_bytes(got.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let got: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(got["ready"], false);
+        assert_eq!(got["fallback"], serde_json::json!({"ok": true}));
+        assert_eq!(got["cmyk_profile"]["ok"], false);
+        let providers = got["providers"].as_array().unwrap();
+        assert_eq!(providers.len(), 3);
+        assert_eq!(providers[0]["ok"], true);
+        assert_eq!(providers[1]["ok"], true);
+        assert_eq!(providers[1]["sources"][0]["ok"], false);
+        assert_eq!(providers[1]["sources"][1]["ok"], true);
+        assert_eq!(providers[1]["sources"][1]["index"], 1);
+        assert!(providers[1]["sources"][1].get("src").is_none());
+        assert_eq!(providers[2]["path"], "missing");
+        assert_eq!(providers[2]["ok"], false);
+        mock_server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_relay_within() {
+        use futures_util::StreamExt;
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+        let body = relay_within(Body::from("lenna"), deadline);
+        let got = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(got, "lenna");
+
+        // A stalled origin is cut off rather than holding the response.
+        let stalled = futures_util::stream::once(async {
+            Ok::<_, std::io::Error>(bytes::Bytes::from("len"))
+        })
+        .chain(futures_util::stream::pending());
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_millis(50);
+        let body = relay_within(Body::from_stream(stalled), deadline);
+        assert!(axum::body::to_bytes(body, usize::MAX).await.is_err());
     }
 
     #[test]
@@ -0,0 +1,223 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+const NAMESPACE: &str = "fanlin";
+// Requests matching no provider are counted under this label.
+pub const NO_PROVIDER: &str = "";
+
+// https://prometheus.io/docs/practices/naming/
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    fetch_seconds: HistogramVec,
+    process_seconds: HistogramVec,
+    origin_errors: IntCounterVec,
+    fallbacks: IntCounterVec,
+    in_flight: IntGauge,
+    max_clients: IntGauge,
+    input_bytes: HistogramVec,
+    output_bytes: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let opts = |name: &str, help: &str| Opts::new(name, help).namespace(NAMESPACE);
+        let seconds = |name: &str, help: &str| {
+            HistogramOpts::from(opts(name, help)).buckets(Vec::from([
+                0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+            ]))
+        };
+        let bytes = |name: &str, help: &str| {
+            // 1KiB to 16MiB
+            HistogramOpts::from(opts(name, help))
+                .buckets(prometheus::exponential_buckets(1024.0, 4.0, 8).unwrap())
+        };
+        let requests = IntCounterVec::new(
+            opts(
+                "requests_total",
+                "Requests by provider, status and output format.",
+            ),
+            &["provider", "status", "format"],
+        )
+        .unwrap();
+        let fetch_seconds = HistogramVec::new(
+            seconds("fetch_seconds", "Time to fetch an original image."),
+            &["provider"],
+        )
+        .unwrap();
+        let process_seconds = HistogramVec::new(
+            seconds("process_seconds", "Time to process an image."),
+            &["provider"],
+        )
+        .unwrap();
+        let origin_errors = IntCounterVec::new(
+            opts(
+                "origin_errors_total",
+                "Errors fetching from origins by scheme.",
+            ),
+            &["scheme"],
+        )
+        .unwrap();
+        let fallbacks = IntCounterVec::new(
+            opts("fallbacks_total", "Fallback images served by provider."),
+            &["provider"],
+        )
+        .unwrap();
+        let in_flight =
+            IntGauge::with_opts(opts("in_flight_requests", "Requests in flight.")).unwrap();
+        let max_clients = IntGauge::with_opts(opts(
+            "max_clients",
+            "Requests allowed to be served at the same time.",
+        ))
+        .unwrap();
+        let input_bytes = HistogramVec::new(
+            bytes("input_bytes", "Sizes of original images."),
+            &["provider"],
+        )
+        .unwrap();
+        let output_bytes = Histogram::with_opts(bytes(
+            "output_bytes",
+            "Sizes of response bodies known in advance.",
+        ))
+        .unwrap();
+        let collectors: [Box<dyn prometheus::core::Collector>; 9] = [
+            Box::new(requests.clone()),
+            Box::new(fetch_seconds.clone()),
+            Box::new(process_seconds.clone()),
+            Box::new(origin_errors.clone()),
+            Box::new(fallbacks.clone()),
+            Box::new(in_flight.clone()),
+            Box::new(max_clients.clone()),
+            Box::new(input_bytes.clone()),
+            Box::new(output_bytes.clone()),
+        ];
+        for c in collectors {
+            registry.register(c).expect("failed to register a metric");
+        }
+        Self {
+            registry,
+            requests,
+            fetch_seconds,
+            process_seconds,
+            origin_errors,
+            fallbacks,
+            in_flight,
+            max_clients,
+            input_bytes,
+            output_bytes,
+        }
+    }
+
+    pub fn set_max_clients(&self, n: usize) {
+        self.max_clients.set(n as i64);
+    }
+
+    // Counts a request in flight until the returned guard is dropped.
+    pub fn track_in_flight(&self) -> InFlight {
+        self.in_flight.inc();
+        InFlight(self.in_flight.clone())
+    }
+
+    pub fn observe_response(
+        &self,
+        provider: &str,
+        status: u16,
+        content_type: &str,
+        size: Option<u64>,
+    ) {
+        let format = content_type.split(';').next().unwrap_or_default().trim();
+        self.requests
+            .with_label_values(&[provider, status.to_string().as_str(), format])
+            .inc();
+        if let Some(size) = size {
+            self.output_bytes.observe(size as f64);
+        }
+    }
+
+    pub fn observe_fetch(&self, provider: &str, elapsed: std::time::Duration, size: Option<usize>) {
+        self.fetch_seconds
+            .with_label_values(&[provider])
+            .observe(elapsed.as_secs_f64());
+        if let Some(size) = size {
+            self.input_bytes
+                .with_label_values(&[provider])
+                .observe(size as f64);
+        }
+    }
+
+    pub fn observe_process(&self, provider: &str, elapsed: std::time::Duration) {
+        self.process_seconds
+            .with_label_values(&[provider])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn count_origin_error(&self, scheme: &str) {
+        self.origin_errors.with_label_values(&[scheme]).inc();
+    }
+
+    pub fn count_fallback(&self, provider: &str) {
+        self.fallbacks.with_label_values(&[provider]).inc();
+    }
+
+    // https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
+    pub fn render(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+#[derive(Debug)]
+pub struct InFlight(IntGauge);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.set_max_clients(1024);
+        let guard = metrics.track_in_flight();
+        metrics.observe_response("foo", 200, "image/webp", Some(2048));
+        metrics.observe_response(NO_PROVIDER, 404, "text/plain; charset=utf-8", None);
+        metrics.observe_fetch("foo", std::time::Duration::from_millis(20), Some(4096));
+        metrics.observe_process("foo", std::time::Duration::from_millis(30));
+        metrics.count_origin_error("s3");
+        metrics.count_fallback("foo");
+        let got = metrics.render().expect("failed to render metrics");
+        for want in [
+            "fanlin_max_clients 1024",
+            "fanlin_in_flight_requests 1",
+            r#"fanlin_requests_total{format="image/webp",provider="foo",status="200"} 1"#,
+            r#"fanlin_requests_total{format="text/plain",provider="",status="404"} 1"#,
+            r#"fanlin_fetch_seconds_count{provider="foo"} 1"#,
+            r#"fanlin_process_seconds_count{provider="foo"} 1"#,
+            r#"fanlin_input_bytes_sum{provider="foo"} 4096"#,
+            "fanlin_output_bytes_sum 2048",
+            r#"fanlin_origin_errors_total{scheme="s3"} 1"#,
+            r#"fanlin_fallbacks_total{provider="foo"} 1"#,
+        ] {
+            assert!(got.contains(want), "want: {want}, got: {got}");
+        }
+        drop(guard);
+        let got = metrics.render().unwrap();
+        assert!(got.contains("fanlin_in_flight_requests 0"));
+    }
+}
@@ -0,0 +1,148 @@
+use super::config::processing;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_QUEUE_DEPTH: usize = 1024;
+const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Runs CPU-bound work on its own threads so that it never blocks the async runtime.
+#[derive(Debug)]
+pub struct Pool {
+    sender: SyncSender<Job>,
+    queue_timeout: Duration,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejected {
+    QueueFull,
+    QueueTimeout,
+    Aborted,
+}
+
+impl Pool {
+    pub fn new(cfg: Option<processing::Config>) -> Self {
+        let (threads, queue_depth, queue_timeout) = match cfg {
+            Some(c) => (
+                c.threads,
+                c.queue_depth.get(),
+                Duration::from_millis(c.queue_timeout_ms),
+            ),
+            None => (
+                std::thread::available_parallelism().map_or(1, |v| v.get()),
+                DEFAULT_QUEUE_DEPTH,
+                DEFAULT_QUEUE_TIMEOUT,
+            ),
+        };
+        let (sender, receiver) = sync_channel::<Job>(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("fanlin-processing-{i}"))
+                .spawn(move || work(receiver))
+                .expect("failed to spawn a processing thread");
+        }
+        Self {
+            sender,
+            queue_timeout,
+        }
+    }
+
+    // Only a job still in the queue is skipped once its caller gives up,
+    // and a running one keeps its thread until it is done even after the caller timed out.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, Rejected>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        // https://docs.rs/tokio/latest/tokio/sync/oneshot/index.html
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let enqueued_at = Instant::now();
+        let queue_timeout = self.queue_timeout;
+        let job: Job = Box::new(move || {
+            if enqueued_at.elapsed() > queue_timeout {
+                let _ = tx.send(Err(Rejected::QueueTimeout));
+                return;
+            }
+            // Nobody waits for the result once the request is gone.
+            if tx.is_closed() {
+                return;
+            }
+            let _ = tx.send(Ok(f()));
+        });
+        match self.sender.try_send(job) {
+            Ok(()) => rx.await.unwrap_or(Err(Rejected::Aborted)),
+            Err(TrySendError::Full(_)) => Err(Rejected::QueueFull),
+            Err(TrySendError::Disconnected(_)) => Err(Rejected::Aborted),
+        }
+    }
+}
+
+fn work(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = {
+            let receiver = receiver.lock().unwrap_or_else(|e| e.into_inner());
+            match receiver.recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            }
+        };
+        // A panicking job drops its sender, which the waiting request sees as aborted.
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run() {
+        let pool = Pool::new(Some(processing::Config {
+            threads: 2,
+            queue_depth: std::num::NonZeroUsize::new(4).unwrap(),
+            queue_timeout_ms: 1000,
+        }));
+        assert_eq!(pool.run(|| 1 + 1).await, Ok(2));
+        assert_eq!(
+            pool.run(|| -> u32 { panic!("boom") }).await,
+            Err(Rejected::Aborted)
+        );
+        assert_eq!(pool.run(|| "alive").await, Ok("alive"));
+    }
+
+    #[tokio::test]
+    async fn test_shedding() {
+        let pool = Arc::new(Pool::new(Some(processing::Config {
+            threads: 1,
+            queue_depth: std::num::NonZeroUsize::MIN,
+            queue_timeout_ms: 50,
+        })));
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let busy = {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                pool.run(move || {
+                    let _ = blocked.recv();
+                })
+                .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let queued = {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.run(|| ()).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(pool.run(|| ()).await, Err(Rejected::QueueFull));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        release.send(()).unwrap();
+        assert_eq!(busy.await.unwrap(), Ok(()));
+        assert_eq!(queued.await.unwrap(), Err(Rejected::QueueTimeout));
+        assert_eq!(pool.run(|| ()).await, Ok(()));
+    }
+}
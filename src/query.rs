@@ -1,6 +1,6 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Query {
     w: Option<u32>,
     h: Option<u32>,
@@ -12,11 +12,28 @@ pub struct Query {
     inverse: Option<bool>,
     avif: Option<bool>,
     webp: Option<bool>,
+    info: Option<bool>,
+    lqip: Option<bool>,
+    wm: Option<bool>,
+    wm_pos: Option<String>,
+    wm_margin: Option<u32>,
+    wm_opacity: Option<u8>,
+    wm_scale: Option<u8>,
+    text: Option<String>,
+    font: Option<String>,
+    text_size: Option<u32>,
+    text_color: Option<String>,
+    text_pos: Option<String>,
+    text_bg: Option<String>,
 }
 
 const DEFAULT_COLOR: u8 = 32;
 const DEFAULT_QUALITY: u8 = 75;
 const DEFAULT_BLUR_SIGMA: f32 = 0.0;
+const DEFAULT_TEXT_SIZE: u32 = 24;
+const DEFAULT_TEXT_COLOR: (u8, u8, u8, u8) = (255, 255, 255, 255);
+const TEXT_SIZE_RANGE: std::ops::RangeInclusive<u32> = 8..=200;
+const MAX_WATERMARK_MARGIN: u32 = 1000;
 const WIDTH_RANGE: std::ops::RangeInclusive<u32> = 20..=2000;
 const HEIGHT_RANGE: std::ops::RangeInclusive<u32> = 20..=1000;
 
@@ -77,6 +94,75 @@ impl Query {
         self.webp.is_some_and(|v| v)
     }
 
+    pub fn info(&self) -> bool {
+        self.info.is_some_and(|v| v)
+    }
+
+    pub fn watermark(&self) -> bool {
+        self.wm.is_some_and(|v| v)
+    }
+
+    pub fn watermark_position(&self) -> Option<&str> {
+        self.wm_pos.as_deref()
+    }
+
+    pub fn watermark_margin(&self) -> Option<u32> {
+        self.wm_margin.map(|v| v.min(MAX_WATERMARK_MARGIN))
+    }
+
+    pub fn watermark_opacity(&self) -> Option<u8> {
+        self.wm_opacity
+    }
+
+    pub fn watermark_scale(&self) -> Option<u8> {
+        self.wm_scale
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_deref().filter(|v| !v.is_empty())
+    }
+
+    pub fn font(&self) -> Option<&str> {
+        self.font.as_deref()
+    }
+
+    pub fn text_size(&self) -> u32 {
+        self.text_size.map_or(DEFAULT_TEXT_SIZE, |v| {
+            v.clamp(*TEXT_SIZE_RANGE.start(), *TEXT_SIZE_RANGE.end())
+        })
+    }
+
+    pub fn text_color(&self) -> (u8, u8, u8, u8) {
+        self.text_color
+            .as_deref()
+            .and_then(parse_rgba)
+            .unwrap_or(DEFAULT_TEXT_COLOR)
+    }
+
+    pub fn text_position(&self) -> Option<&str> {
+        self.text_pos.as_deref()
+    }
+
+    pub fn text_background(&self) -> Option<(u8, u8, u8, u8)> {
+        self.text_bg.as_deref().and_then(parse_rgba)
+    }
+
+    pub fn lqip(&self) -> bool {
+        self.lqip.is_some_and(|v| v)
+    }
+
+    // Whether JSON about an original is asked instead of an image.
+    pub fn describes(&self) -> bool {
+        self.info() || self.lqip()
+    }
+
+    pub fn with_info(self) -> Self {
+        Self {
+            info: Some(true),
+            ..self
+        }
+    }
+
     pub fn as_is(&self) -> bool {
         self.dimensions().is_none()
             && self.blur() == DEFAULT_BLUR_SIGMA
@@ -84,6 +170,41 @@ impl Query {
             && !self.inverse()
             && !self.use_avif()
             && !self.use_webp()
+            && !self.watermark()
+            && self.text().is_none()
+    }
+
+    pub fn with_defaults(self, defaults: &Query) -> Self {
+        Self {
+            w: self.w.or(defaults.w),
+            h: self.h.or(defaults.h),
+            rgb: self.rgb.or_else(|| defaults.rgb.clone()),
+            quality: self.quality.or(defaults.quality),
+            crop: self.crop.or(defaults.crop),
+            blur: self.blur.or(defaults.blur),
+            grayscale: self.grayscale.or(defaults.grayscale),
+            inverse: self.inverse.or(defaults.inverse),
+            avif: self.avif.or(defaults.avif),
+            webp: self.webp.or(defaults.webp),
+            info: self.info.or(defaults.info),
+            lqip: self.lqip.or(defaults.lqip),
+            wm: self.wm.or(defaults.wm),
+            wm_pos: self.wm_pos.or_else(|| defaults.wm_pos.clone()),
+            wm_margin: self.wm_margin.or(defaults.wm_margin),
+            wm_opacity: self.wm_opacity.or(defaults.wm_opacity),
+            wm_scale: self.wm_scale.or(defaults.wm_scale),
+            text: self.text.or_else(|| defaults.text.clone()),
+            font: self.font.or_else(|| defaults.font.clone()),
+            text_size: self.text_size.or(defaults.text_size),
+            text_color: self.text_color.or_else(|| defaults.text_color.clone()),
+            text_pos: self.text_pos.or_else(|| defaults.text_pos.clone()),
+            text_bg: self.text_bg.or_else(|| defaults.text_bg.clone()),
+        }
+    }
+
+    // Gives the parameters in a fixed order without unknown ones, for logs.
+    pub fn normalized(&self) -> String {
+        serde_urlencoded::to_string(self).unwrap_or_default()
     }
 
     pub fn unsupported_scale_size(&self) -> bool {
@@ -93,10 +214,37 @@ impl Query {
     }
 }
 
+// Reads `r,g,b` or `r,g,b,a`, where the alpha defaults to opaque.
+fn parse_rgba(text: &str) -> Option<(u8, u8, u8, u8)> {
+    let v = text
+        .split(',')
+        .map(|e| e.trim().parse::<u8>().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    match v[..] {
+        [r, g, b] => Some((r, g, b, 255)),
+        [r, g, b, a] => Some((r, g, b, a)),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_normalized() {
+        let cases = [
+            ("", ""),
+            ("unknown=1", ""),
+            ("webp=true&h=200&w=300", "w=300&h=200&webp=true"),
+            ("rgb=1,2,3&quality=50", "rgb=1%2C2%2C3&quality=50"),
+        ];
+        for (query_string, want) in cases {
+            let got: Query = serde_urlencoded::from_str(query_string).unwrap();
+            assert_eq!(got.normalized(), want, "{query_string}");
+        }
+    }
+
     #[test]
     fn test_query() {
         struct Case {
@@ -371,6 +519,110 @@ mod tests {
                     assert!(!got.as_is());
                 },
             },
+            Case {
+                query_string: "http://127.0.0.1:3000?info=true",
+                error: false,
+                want: Query {
+                    info: Some(true),
+                    ..Default::default()
+                },
+                assert: |got| {
+                    assert!(got.info());
+                    assert!(got.describes());
+                    assert!(got.as_is());
+                },
+            },
+            Case {
+                query_string: "http://127.0.0.1:3000?lqip=true",
+                error: false,
+                want: Query {
+                    lqip: Some(true),
+                    ..Default::default()
+                },
+                assert: |got| {
+                    assert!(got.lqip());
+                    assert!(got.describes());
+                },
+            },
+            Case {
+                query_string: "http://127.0.0.1:3000?wm=true&wm_pos=top-left&wm_margin=8&wm_opacity=50&wm_scale=20",
+                error: false,
+                want: Query {
+                    wm: Some(true),
+                    wm_pos: Some("top-left".to_string()),
+                    wm_margin: Some(8),
+                    wm_opacity: Some(50),
+                    wm_scale: Some(20),
+                    ..Default::default()
+                },
+                assert: |got| {
+                    assert!(got.watermark());
+                    assert_eq!(got.watermark_position(), Some("top-left"));
+                    assert_eq!(got.watermark_margin(), Some(8));
+                    assert_eq!(got.watermark_opacity(), Some(50));
+                    assert_eq!(got.watermark_scale(), Some(20));
+                    assert!(!got.as_is());
+                },
+            },
+            Case {
+                query_string: "http://127.0.0.1:3000?wm=true&wm_margin=100000",
+                error: false,
+                want: Query {
+                    wm: Some(true),
+                    wm_margin: Some(100000),
+                    ..Default::default()
+                },
+                assert: |got| {
+                    assert_eq!(got.watermark_margin(), Some(1000));
+                },
+            },
+            Case {
+                query_string: "http://127.0.0.1:3000?text=SOLD&font=DejaVuSans&text_size=500&text_color=255,0,0&text_pos=top-right&text_bg=0,0,0,128",
+                error: false,
+                want: Query {
+                    text: Some("SOLD".to_string()),
+                    font: Some("DejaVuSans".to_string()),
+                    text_size: Some(500),
+                    text_color: Some("255,0,0".to_string()),
+                    text_pos: Some("top-right".to_string()),
+                    text_bg: Some("0,0,0,128".to_string()),
+                    ..Default::default()
+                },
+                assert: |got| {
+                    assert_eq!(got.text(), Some("SOLD"));
+                    assert_eq!(got.font(), Some("DejaVuSans"));
+                    assert_eq!(got.text_size(), 200);
+                    assert_eq!(got.text_color(), (255, 0, 0, 255));
+                    assert_eq!(got.text_position(), Some("top-right"));
+                    assert_eq!(got.text_background(), Some((0, 0, 0, 128)));
+                    assert!(!got.as_is());
+                },
+            },
+            Case {
+                query_string: "http://127.0.0.1:3000?text=&text_color=foo&text_bg=1,2",
+                error: false,
+                want: Query {
+                    text: Some("".to_string()),
+                    text_color: Some("foo".to_string()),
+                    text_bg: Some("1,2".to_string()),
+                    ..Default::default()
+                },
+                assert: |got| {
+                    assert_eq!(got.text(), None);
+                    assert_eq!(got.text_size(), 24);
+                    assert_eq!(got.text_color(), (255, 255, 255, 255));
+                    assert_eq!(got.text_background(), None);
+                    assert!(got.as_is());
+                },
+            },
+            Case {
+                query_string: "http://127.0.0.1:3000?wm_opacity=300",
+                error: true,
+                want: Query {
+                    ..Default::default()
+                },
+                assert: |_| {},
+            },
             Case {
                 query_string: "http://127.0.0.1:3000?webp=foo",
                 error: true,
@@ -403,4 +655,34 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_with_defaults() {
+        let defaults = Query {
+            w: Some(150),
+            h: Some(150),
+            quality: Some(50),
+            ..Default::default()
+        };
+        let got = Query {
+            w: Some(300),
+            webp: Some(true),
+            ..Default::default()
+        }
+        .with_defaults(&defaults);
+        assert_eq!(
+            got,
+            Query {
+                w: Some(300),
+                h: Some(150),
+                quality: Some(50),
+                webp: Some(true),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            Query::default().with_defaults(&Query::default()),
+            Query::default()
+        );
+    }
 }
@@ -0,0 +1,310 @@
+use super::config::rate_limit;
+use super::content;
+use super::query;
+use axum::http::header;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The least recently seen client is forgotten once this many clients are tracked.
+const MAX_TRACKED_CLIENTS: NonZeroUsize = NonZeroUsize::new(65536).unwrap();
+
+// https://en.wikipedia.org/wiki/Token_bucket
+#[derive(Debug)]
+pub struct Limiter {
+    per_second: f64,
+    burst: f64,
+    costs: rate_limit::Costs,
+    buckets: Mutex<lru::LruCache<IpAddr, Bucket>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Limiter {
+    pub fn new(cfg: rate_limit::Config) -> Self {
+        Self::with_max_clients(cfg, MAX_TRACKED_CLIENTS)
+    }
+
+    fn with_max_clients(cfg: rate_limit::Config, max_clients: NonZeroUsize) -> Self {
+        if cfg.per_second <= 0.0 || cfg.burst <= 0.0 {
+            panic!("rate_limit per_second and burst must be positive");
+        }
+        Self {
+            per_second: cfg.per_second,
+            burst: cfg.burst,
+            costs: cfg.costs.unwrap_or_default(),
+            buckets: Mutex::new(lru::LruCache::new(max_clients)),
+        }
+    }
+
+    // Gives how long the client should wait when it runs out of tokens.
+    pub fn try_acquire(
+        &self,
+        client: IpAddr,
+        params: &query::Query,
+        content: content::Format,
+    ) -> Result<(), Duration> {
+        let cost = self.cost(params, content).min(self.burst);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        // Buckets of clients unseen for a while are refilled to the full, which is the same as none.
+        while buckets
+            .peek_lru()
+            .is_some_and(|(_, b)| self.refill(b, now) >= self.burst)
+        {
+            buckets.pop_lru();
+        }
+        // https://docs.rs/lru/latest/lru/struct.LruCache.html#method.get_or_insert_mut
+        let bucket = buckets.get_or_insert_mut(client, || Bucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+        *bucket = Bucket {
+            tokens: self.refill(bucket, now),
+            updated_at: now,
+        };
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (cost - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+
+    // Gives back what a request took, when another limiter refused it afterwards.
+    pub fn refund(&self, client: IpAddr, params: &query::Query, content: content::Format) {
+        let cost = self.cost(params, content).min(self.burst);
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(bucket) = buckets.get_mut(&client) {
+            bucket.tokens = (bucket.tokens + cost).min(self.burst);
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        (bucket.tokens + elapsed.as_secs_f64() * self.per_second).min(self.burst)
+    }
+
+    // A request takes the largest cost among the operations it uses,
+    // where a format is counted only when it is encoded as negotiated with `Accept`.
+    fn cost(&self, params: &query::Query, content: content::Format) -> f64 {
+        let mut cost: f64 = 1.0;
+        if params.use_webp() && content.webp_accepted() {
+            cost = cost.max(self.costs.webp.unwrap_or(1.0));
+        } else if params.use_avif() && content.avif_accepted() {
+            cost = cost.max(self.costs.avif.unwrap_or(1.0));
+        }
+        if params.blur() > 0.0 {
+            cost = cost.max(self.costs.blur.unwrap_or(1.0));
+        }
+        if params.text().is_some() {
+            cost = cost.max(self.costs.text.unwrap_or(1.0));
+        }
+        cost
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    pub fn new(list: &[String]) -> Self {
+        let networks = list
+            .iter()
+            .map(|v| parse_network(v).expect("failed to parse a trusted proxy"))
+            .collect();
+        Self { networks }
+    }
+
+    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/X-Forwarded-For#selecting_an_ip_address
+    pub fn client_ip(&self, peer: IpAddr, headers: &header::HeaderMap) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+        let forwarded: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|v| v.trim().parse::<IpAddr>().ok())
+            .collect();
+        forwarded
+            .iter()
+            .rev()
+            .find(|ip| !self.contains(**ip))
+            .or(forwarded.first())
+            .copied()
+            .unwrap_or(peer)
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.networks
+            .iter()
+            .any(|(network, prefix)| match (network, ip) {
+                (IpAddr::V4(n), IpAddr::V4(ip)) => {
+                    let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                    u32::from(*n) & mask == u32::from(ip) & mask
+                }
+                (IpAddr::V6(n), IpAddr::V6(ip)) => {
+                    let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                    u128::from(*n) & mask == u128::from(ip) & mask
+                }
+                _ => false,
+            })
+    }
+}
+
+fn parse_network(text: &str) -> Result<(IpAddr, u8), Box<dyn std::error::Error>> {
+    let (addr, prefix) = match text.split_once('/') {
+        Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+        None => (text.parse::<IpAddr>()?, None),
+    };
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(max);
+    if prefix > max {
+        return Err(Box::from(format!("prefix length is too long; {text}")));
+    }
+    Ok((addr, prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_acquire() {
+        let limiter = Limiter::new(rate_limit::Config {
+            per_second: 1.0,
+            burst: 3.0,
+            costs: Some(rate_limit::Costs {
+                avif: Some(3.0),
+                blur: Some(10.0),
+                text: Some(2.0),
+                ..Default::default()
+            }),
+        });
+        let client = IpAddr::from([192, 0, 2, 1]);
+        let other = IpAddr::from([192, 0, 2, 2]);
+        let plain = query::Query::default();
+        let avif: query::Query = serde_urlencoded::from_str("avif=true").unwrap();
+        let blur: query::Query = serde_urlencoded::from_str("blur=15").unwrap();
+        let text: query::Query = serde_urlencoded::from_str("text=SOLD").unwrap();
+        let none = content::Format::new();
+        let mut accepting = content::Format::new();
+        accepting.accept_avif();
+
+        assert_eq!(limiter.cost(&plain, accepting), 1.0);
+        assert_eq!(limiter.cost(&avif, accepting), 3.0);
+        assert_eq!(limiter.cost(&blur, accepting), 10.0);
+        assert_eq!(limiter.cost(&text, accepting), 2.0);
+        // AVIF is not encoded for a client which does not accept it.
+        assert_eq!(limiter.cost(&avif, none), 1.0);
+
+        assert!(limiter.try_acquire(client, &plain, accepting).is_ok());
+        assert!(limiter.try_acquire(client, &plain, accepting).is_ok());
+        let wait = limiter.try_acquire(client, &avif, accepting).unwrap_err();
+        assert!(wait > Duration::from_millis(1500) && wait <= Duration::from_secs(2));
+        assert!(limiter.try_acquire(client, &plain, accepting).is_ok());
+        assert!(limiter.try_acquire(client, &plain, accepting).is_err());
+
+        // A cost over the burst would never be satisfied, so it takes the whole bucket instead.
+        assert!(limiter.try_acquire(other, &blur, accepting).is_ok());
+        assert!(limiter.try_acquire(other, &plain, accepting).is_err());
+
+        // What is given back is bounded by the burst.
+        let third = IpAddr::from([192, 0, 2, 3]);
+        assert!(limiter.try_acquire(third, &avif, accepting).is_ok());
+        limiter.refund(third, &avif, accepting);
+        limiter.refund(third, &avif, accepting);
+        assert!(limiter.try_acquire(third, &avif, accepting).is_ok());
+        assert!(limiter.try_acquire(third, &plain, accepting).is_err());
+    }
+
+    #[test]
+    fn test_tracked_clients() {
+        let cfg = rate_limit::Config {
+            per_second: 100.0,
+            burst: 1.0,
+            costs: None,
+        };
+        let limiter = Limiter::with_max_clients(cfg.clone(), NonZeroUsize::new(2).unwrap());
+        let plain = query::Query::default();
+        let accepting = content::Format::new();
+        for i in 1..=3 {
+            assert!(limiter
+                .try_acquire(IpAddr::from([192, 0, 2, i]), &plain, accepting)
+                .is_ok());
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+
+        // Refilled buckets are dropped as requests come.
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter
+            .try_acquire(IpAddr::from([192, 0, 2, 4]), &plain, accepting)
+            .is_ok());
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+
+        // A client forgotten by the bound starts with a full bucket again.
+        let limiter = Limiter::with_max_clients(
+            rate_limit::Config {
+                per_second: 0.001,
+                ..cfg
+            },
+            NonZeroUsize::new(1).unwrap(),
+        );
+        let client = IpAddr::from([192, 0, 2, 1]);
+        assert!(limiter.try_acquire(client, &plain, accepting).is_ok());
+        assert!(limiter.try_acquire(client, &plain, accepting).is_err());
+        assert!(limiter
+            .try_acquire(IpAddr::from([192, 0, 2, 2]), &plain, accepting)
+            .is_ok());
+        assert!(limiter.try_acquire(client, &plain, accepting).is_ok());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxies = TrustedProxies::new(&[
+            "10.0.0.0/8".to_string(),
+            "192.0.2.10".to_string(),
+            "2001:db8::/32".to_string(),
+        ]);
+        let headers = |v: &str| {
+            let mut headers = header::HeaderMap::new();
+            headers.insert("x-forwarded-for", header::HeaderValue::from_str(v).unwrap());
+            headers
+        };
+        for (peer, forwarded, want) in [
+            ("203.0.113.1", Some("198.51.100.1"), "203.0.113.1"),
+            ("10.1.2.3", None, "10.1.2.3"),
+            ("10.1.2.3", Some("198.51.100.1"), "198.51.100.1"),
+            (
+                "10.1.2.3",
+                Some("198.51.100.9, 198.51.100.1, 192.0.2.10"),
+                "198.51.100.1",
+            ),
+            ("10.1.2.3", Some("10.0.0.1, 10.0.0.2"), "10.0.0.1"),
+            ("10.1.2.3", Some("unknown"), "10.1.2.3"),
+            ("2001:db8::1", Some("2001:db9::1"), "2001:db9::1"),
+        ] {
+            let peer = peer.parse::<IpAddr>().unwrap();
+            let headers = forwarded.map_or_else(header::HeaderMap::new, headers);
+            let got = proxies.client_ip(peer, &headers);
+            assert_eq!(
+                got,
+                want.parse::<IpAddr>().unwrap(),
+                "case: {peer} {forwarded:?}"
+            );
+        }
+        assert!(parse_network("10.0.0.0/33").is_err());
+        assert!(parse_network("example.com").is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use super::config::telemetry;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::WithExportConfig;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+const DEFAULT_SERVICE_NAME: &str = "fanlin-rs";
+
+// https://opentelemetry.io/docs/languages/rust/exporters/
+pub fn init_tracer_provider(
+    cfg: telemetry::Config,
+) -> Result<opentelemetry_sdk::trace::SdkTracerProvider, Box<dyn std::error::Error>> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(cfg.otlp_endpoint)
+        .build()?;
+    let resource = opentelemetry_sdk::Resource::builder()
+        .with_service_name(
+            cfg.service_name
+                .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string()),
+        )
+        .build();
+    // https://opentelemetry.io/docs/concepts/sampling/
+    let sampler = opentelemetry_sdk::trace::Sampler::ParentBased(Box::new(
+        opentelemetry_sdk::trace::Sampler::TraceIdRatioBased(cfg.sample_ratio.unwrap_or(1.0)),
+    ));
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .with_sampler(sampler)
+        .build();
+    // https://www.w3.org/TR/trace-context/
+    opentelemetry::global::set_text_map_propagator(
+        opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+    );
+    Ok(provider)
+}
+
+pub fn layer<S>(
+    provider: &opentelemetry_sdk::trace::SdkTracerProvider,
+) -> tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(DEFAULT_SERVICE_NAME))
+}
+
+// Makes the same span as DefaultMakeSpan, continuing the trace given by `traceparent`.
+// https://docs.rs/tower-http/latest/tower_http/trace/trait.MakeSpan.html
+pub fn make_span<B>(request: &axum::http::Request<B>) -> tracing::Span {
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+    );
+    let parent = opentelemetry::global::get_text_map_propagator(|p| {
+        p.extract(&opentelemetry_http::HeaderExtractor(request.headers()))
+    });
+    if let Err(err) = span.set_parent(parent) {
+        tracing::debug!("failed to set a parent span; {err:?}");
+    }
+    span
+}
+
+// Gives `traceparent` of the current span for outgoing requests.
+pub fn propagation_headers() -> axum::http::HeaderMap {
+    let mut headers = axum::http::HeaderMap::new();
+    let cx = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|p| {
+        p.inject_context(&cx, &mut opentelemetry_http::HeaderInjector(&mut headers))
+    });
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn test_propagation() {
+        let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder().build();
+        opentelemetry::global::set_text_map_propagator(
+            opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+        );
+        let subscriber = tracing_subscriber::registry().with(layer(&provider));
+        tracing::subscriber::with_default(subscriber, || {
+            let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+            let request = axum::http::Request::builder()
+                .uri("/foo/lenna.jpg")
+                .header("traceparent", format!("00-{trace_id}-00f067aa0ba902b7-01"))
+                .body(())
+                .unwrap();
+            let span = make_span(&request);
+            let _entered = span.enter();
+            let _fetch = tracing::info_span!("web.get").entered();
+            let got = propagation_headers();
+            let traceparent = got["traceparent"].to_str().unwrap();
+            assert!(
+                traceparent.starts_with(format!("00-{trace_id}-").as_str()),
+                "got: {traceparent}"
+            );
+            assert!(
+                !traceparent.contains("00f067aa0ba902b7"),
+                "got: {traceparent}"
+            );
+        });
+        assert!(propagation_headers().is_empty());
+    }
+}
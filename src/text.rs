@@ -0,0 +1,235 @@
+use super::config::text;
+use super::query;
+use super::watermark::Position;
+use ab_glyph::{Font, FontVec, PxScale, ScaleFont};
+use image::{Pixel, Rgba, RgbaImage};
+use std::collections::HashMap;
+
+const MAX_TEXT_CHARS: usize = 256;
+const MAX_TEXT_LINES: usize = 8;
+// A text is drawn smaller than asked for when its layer would be larger than this.
+const MAX_LAYER_PIXELS: f32 = 1_000_000.0;
+
+// Fonts bundled in a directory, named by their file stems such as `DejaVuSans`.
+#[derive(Debug)]
+pub struct Fonts {
+    fonts: HashMap<String, FontVec>,
+    default_font: String,
+}
+
+impl Fonts {
+    pub fn load(cfg: &text::Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut fonts = HashMap::new();
+        for entry in std::fs::read_dir(&cfg.font_dir)? {
+            let path = entry?.path();
+            let is_font = path
+                .extension()
+                .and_then(|v| v.to_str())
+                .is_some_and(|v| matches!(v.to_ascii_lowercase().as_str(), "ttf" | "otf"));
+            let Some(name) = path.file_stem().and_then(|v| v.to_str()) else {
+                continue;
+            };
+            if !is_font {
+                continue;
+            }
+            // https://docs.rs/ab_glyph/latest/ab_glyph/struct.FontVec.html
+            let font = FontVec::try_from_vec(std::fs::read(&path)?)
+                .map_err(|err| format!("failed to load a font; {} {err}", path.display()))?;
+            fonts.insert(name.to_string(), font);
+        }
+        let default_font = match &cfg.default_font {
+            Some(v) => v.clone(),
+            None => {
+                let mut names: Vec<_> = fonts.keys().collect();
+                names.sort();
+                names.first().map(|v| v.to_string()).unwrap_or_default()
+            }
+        };
+        if !fonts.contains_key(&default_font) {
+            return Err(Box::from(format!("font not found; {default_font}")));
+        }
+        Ok(Self {
+            fonts,
+            default_font,
+        })
+    }
+
+    pub fn get(&self, name: Option<&str>) -> Option<&FontVec> {
+        self.fonts.get(name.unwrap_or(self.default_font.as_str()))
+    }
+}
+
+// Text drawn onto an image as a layer, with a box behind it if a background is given.
+#[derive(Debug)]
+pub struct Caption {
+    layer: RgbaImage,
+    position: Position,
+    margin: u32,
+}
+
+impl Caption {
+    pub fn new(font: &FontVec, text: &str, params: &query::Query) -> Self {
+        let (r, g, b, a) = params.text_color();
+        let background = params
+            .text_background()
+            .map(|(r, g, b, a)| Rgba([r, g, b, a]));
+        let text: String = text.chars().take(MAX_TEXT_CHARS).collect();
+        let text = text
+            .lines()
+            .take(MAX_TEXT_LINES)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut size = params.text_size();
+        let mut layout = Layout::new(font, &text, size, background.is_some());
+        let area = layout.width * layout.height;
+        if area > MAX_LAYER_PIXELS {
+            let shrink = (MAX_LAYER_PIXELS / area).sqrt();
+            size = ((size as f32 * shrink) as u32).max(1);
+            layout = Layout::new(font, &text, size, background.is_some());
+        }
+        Self {
+            layer: layout.render(font, Rgba([r, g, b, a]), background),
+            position: params
+                .text_position()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(Position::BottomLeft),
+            margin: size / 2,
+        }
+    }
+
+    pub fn apply<I>(&self, img: &mut I)
+    where
+        I: image::GenericImage<Pixel = Rgba<u8>>,
+    {
+        let (x, y) = self
+            .position
+            .origin(img.dimensions(), self.layer.dimensions(), self.margin);
+        image::imageops::overlay(img, &self.layer, x, y);
+    }
+}
+
+// Glyphs placed on a layer, measured before anything is drawn.
+struct Layout {
+    glyphs: Vec<ab_glyph::Glyph>,
+    width: f32,
+    height: f32,
+}
+
+impl Layout {
+    // https://docs.rs/ab_glyph/latest/ab_glyph/trait.ScaleFont.html
+    fn new(font: &FontVec, text: &str, size: u32, boxed: bool) -> Self {
+        let scale = PxScale::from(size as f32);
+        let scaled = font.as_scaled(scale);
+        let line_height = scaled.height() + scaled.line_gap();
+        let padding = if boxed { (size / 4) as f32 } else { 0.0 };
+        let mut glyphs = Vec::new();
+        let mut width = 0f32;
+        let mut lines = 0;
+        for (i, line) in text.lines().enumerate() {
+            let y = padding + i as f32 * line_height + scaled.ascent();
+            let mut x = padding;
+            let mut previous = None;
+            for c in line.chars() {
+                let id = scaled.glyph_id(c);
+                if let Some(p) = previous {
+                    x += scaled.kern(p, id);
+                }
+                glyphs.push(id.with_scale_and_position(scale, ab_glyph::point(x, y)));
+                x += scaled.h_advance(id);
+                previous = Some(id);
+            }
+            width = width.max(x + padding);
+            lines = i + 1;
+        }
+        Self {
+            glyphs,
+            width: width.ceil().max(1.0),
+            height: (lines as f32 * line_height + padding * 2.0).ceil().max(1.0),
+        }
+    }
+
+    fn render(self, font: &FontVec, color: Rgba<u8>, background: Option<Rgba<u8>>) -> RgbaImage {
+        let mut layer = RgbaImage::from_pixel(
+            self.width as u32,
+            self.height as u32,
+            background.unwrap_or(Rgba([0, 0, 0, 0])),
+        );
+        for glyph in self.glyphs {
+            let Some(outlined) = font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outlined.px_bounds();
+            outlined.draw(|x, y, coverage| {
+                let x = x as i64 + bounds.min.x as i64;
+                let y = y as i64 + bounds.min.y as i64;
+                if x < 0 || y < 0 || x >= layer.width() as i64 || y >= layer.height() as i64 {
+                    return;
+                }
+                let alpha = (color[3] as f32 * coverage.clamp(0.0, 1.0)) as u8;
+                let ink = Rgba([color[0], color[1], color[2], alpha]);
+                layer.get_pixel_mut(x as u32, y as u32).blend(&ink);
+            });
+        }
+        layer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caption() {
+        let fonts = Fonts::load(&text::Config {
+            font_dir: "fonts".to_string(),
+            default_font: None,
+        })
+        .expect("failed to load fonts");
+        assert!(fonts.get(Some("DejaVuSans")).is_some());
+        assert!(fonts.get(Some("Unknown")).is_none());
+        let font = fonts.get(None).expect("default font is missing");
+
+        let query = |q: &str| serde_urlencoded::from_str::<query::Query>(q).unwrap();
+        let caption = Caption::new(font, "SOLD", &query("text_size=20&text_color=255,0,0"));
+        let (w, h) = caption.layer.dimensions();
+        assert!(w > 40 && w < 80, "width: {w}");
+        assert_eq!(h, 20);
+        assert_eq!(caption.layer.get_pixel(0, 0)[3], 0);
+        assert!(caption.layer.pixels().any(|p| p == &Rgba([255, 0, 0, 255])));
+
+        let two_lines = Caption::new(font, "SOLD\nOUT", &query("text_size=20"));
+        assert!(two_lines.layer.height() > h * 2 - 2);
+
+        let boxed = Caption::new(
+            font,
+            "SOLD",
+            &query("text_size=20&text_bg=0,0,255&text_pos=top-right"),
+        );
+        assert_eq!(boxed.layer.width(), w + 10);
+        assert_eq!(boxed.layer.get_pixel(0, 0), &Rgba([0, 0, 255, 255]));
+        let mut img = RgbaImage::from_pixel(200, 100, Rgba([0, 0, 0, 255]));
+        boxed.apply(&mut img);
+        assert_eq!(img.get_pixel(200 - 10 - 1, 10), &Rgba([0, 0, 255, 255]));
+        assert_eq!(img.get_pixel(200 - 10, 10), &Rgba([0, 0, 0, 255]));
+
+        // A long text is cut in lines, and drawn smaller to keep its layer small.
+        let long = "SOLD OUT SOLD OUT\n".repeat(16);
+        let capped = Caption::new(font, &long, &query("text_size=200"));
+        let (w, h) = capped.layer.dimensions();
+        assert!((w * h) as f32 <= MAX_LAYER_PIXELS, "size: {w}x{h}");
+        assert_eq!(
+            Caption::new(font, &long, &query("text_size=8"))
+                .layer
+                .height(),
+            Caption::new(font, &"SOLD\n".repeat(8), &query("text_size=8"))
+                .layer
+                .height()
+        );
+
+        assert!(Fonts::load(&text::Config {
+            font_dir: "fonts".to_string(),
+            default_font: Some("Unknown".to_string()),
+        })
+        .is_err());
+    }
+}
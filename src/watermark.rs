@@ -0,0 +1,238 @@
+use super::config::watermark;
+use super::query;
+use image::{imageops, Rgba, RgbaImage};
+
+const DEFAULT_MARGIN: u32 = 0;
+const DEFAULT_OPACITY: u8 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Position {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Position {
+    // Gives where to put an inner image onto an outer one, keeping a margin from the edges.
+    pub fn origin(
+        &self,
+        (width, height): (u32, u32),
+        (w, h): (u32, u32),
+        margin: u32,
+    ) -> (i64, i64) {
+        let m = margin as i64;
+        let start = m;
+        let center = |outer: u32, inner: u32| (outer as i64 - inner as i64) / 2;
+        let end = |outer: u32, inner: u32| outer as i64 - inner as i64 - m;
+        match self {
+            Self::TopLeft => (start, start),
+            Self::Top => (center(width, w), start),
+            Self::TopRight => (end(width, w), start),
+            Self::Left => (start, center(height, h)),
+            Self::Center => (center(width, w), center(height, h)),
+            Self::Right => (end(width, w), center(height, h)),
+            Self::BottomLeft => (start, end(height, h)),
+            Self::Bottom => (center(width, w), end(height, h)),
+            Self::BottomRight => (end(width, w), end(height, h)),
+        }
+    }
+}
+
+impl std::str::FromStr for Position {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "top-left" => Ok(Self::TopLeft),
+            "top" => Ok(Self::Top),
+            "top-right" => Ok(Self::TopRight),
+            "left" => Ok(Self::Left),
+            "center" => Ok(Self::Center),
+            "right" => Ok(Self::Right),
+            "bottom-left" => Ok(Self::BottomLeft),
+            "bottom" => Ok(Self::Bottom),
+            "bottom-right" => Ok(Self::BottomRight),
+            _ => Err(format!("unknown watermark position: {s}")),
+        }
+    }
+}
+
+// Defaults of a provider, which a query may override unless the watermark is enforced.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub path: String,
+    position: Position,
+    margin: u32,
+    opacity: u8,
+    scale: Option<u8>,
+    enforced: bool,
+}
+
+impl Settings {
+    pub fn new(cfg: &watermark::Config) -> Self {
+        let position = cfg.position.as_deref().map_or(Position::BottomRight, |v| {
+            v.parse().expect("failed to parse a watermark position")
+        });
+        Self {
+            path: cfg.path.clone(),
+            position,
+            margin: cfg.margin.unwrap_or(DEFAULT_MARGIN),
+            opacity: cfg.opacity.unwrap_or(DEFAULT_OPACITY).min(100),
+            scale: cfg.scale.map(|v| v.clamp(1, 100)),
+            enforced: cfg.enforced.is_some_and(|v| v),
+        }
+    }
+
+    // Whether the query asks for a watermark or the provider enforces it.
+    pub fn applies(&self, params: &query::Query) -> bool {
+        self.enforced || params.watermark()
+    }
+
+    // An enforced watermark keeps its settings, so that it cannot be made invisible or pushed off an image.
+    pub fn stamp(&self, logo: std::sync::Arc<RgbaImage>, params: &query::Query) -> Option<Stamp> {
+        if !self.applies(params) {
+            return None;
+        }
+        if self.enforced {
+            return Some(Stamp {
+                logo,
+                position: self.position,
+                margin: self.margin,
+                opacity: self.opacity,
+                scale: self.scale,
+            });
+        }
+        Some(Stamp {
+            logo,
+            position: params
+                .watermark_position()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(self.position),
+            margin: params.watermark_margin().unwrap_or(self.margin),
+            opacity: params.watermark_opacity().unwrap_or(self.opacity).min(100),
+            scale: params
+                .watermark_scale()
+                .map(|v| v.clamp(1, 100))
+                .or(self.scale),
+        })
+    }
+}
+
+// A logo to composite onto an image, fitted to the image once it is known.
+#[derive(Clone, Debug)]
+pub struct Stamp {
+    logo: std::sync::Arc<RgbaImage>,
+    position: Position,
+    margin: u32,
+    opacity: u8,
+    scale: Option<u8>,
+}
+
+impl Stamp {
+    // Scales a logo relative to the width of an image, and shrinks it to fit within the margins.
+    pub fn fit(&self, width: u32, height: u32) -> RgbaImage {
+        let (w, h) = self.logo.dimensions();
+        let room_w = width.saturating_sub(self.margin.saturating_mul(2)).max(1);
+        let room_h = height.saturating_sub(self.margin.saturating_mul(2)).max(1);
+        let want_w = self.scale.map_or(w, |v| (width * v as u32 / 100).max(1));
+        let ratio = (want_w as f64 / w as f64)
+            .min(room_w as f64 / w as f64)
+            .min(room_h as f64 / h as f64);
+        let mut logo = if ratio == 1.0 {
+            self.logo.as_ref().clone()
+        } else {
+            let w = ((w as f64 * ratio).round() as u32).max(1);
+            let h = ((h as f64 * ratio).round() as u32).max(1);
+            imageops::resize(self.logo.as_ref(), w, h, imageops::FilterType::Lanczos3)
+        };
+        if self.opacity < 100 {
+            for Rgba([_, _, _, a]) in logo.pixels_mut() {
+                *a = (*a as u32 * self.opacity as u32 / 100) as u8;
+            }
+        }
+        logo
+    }
+
+    // https://docs.rs/image/latest/image/imageops/fn.overlay.html
+    pub fn apply<I>(&self, img: &mut I, fitted: &RgbaImage)
+    where
+        I: image::GenericImage<Pixel = Rgba<u8>>,
+    {
+        let (x, y) = self.origin(img.dimensions(), fitted.dimensions());
+        imageops::overlay(img, fitted, x, y);
+    }
+
+    fn origin(&self, outer: (u32, u32), inner: (u32, u32)) -> (i64, i64) {
+        self.position.origin(outer, inner, self.margin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stamp() {
+        let logo = std::sync::Arc::new(RgbaImage::from_pixel(40, 20, Rgba([255, 0, 0, 200])));
+        let settings = |enforced| {
+            Settings::new(&watermark::Config {
+                path: "/foo/logo.png".to_string(),
+                position: None,
+                margin: Some(10),
+                opacity: Some(50),
+                scale: Some(20),
+                enforced: Some(enforced),
+            })
+        };
+        let query = |q: &str| serde_urlencoded::from_str::<query::Query>(q).unwrap();
+
+        assert!(settings(false).stamp(logo.clone(), &query("")).is_none());
+        assert!(settings(true)
+            .stamp(logo.clone(), &query("wm=false"))
+            .is_some());
+
+        let stamp = settings(false)
+            .stamp(logo.clone(), &query("wm=true"))
+            .unwrap();
+        let fitted = stamp.fit(400, 300);
+        assert_eq!(fitted.dimensions(), (80, 40));
+        assert_eq!(fitted.get_pixel(40, 20)[3], 100);
+        let mut img = RgbaImage::from_pixel(400, 300, Rgba([0, 0, 0, 255]));
+        stamp.apply(&mut img, &fitted);
+        assert_eq!(img.get_pixel(0, 0), &Rgba([0, 0, 0, 255]));
+        assert_ne!(img.get_pixel(389, 289), &Rgba([0, 0, 0, 255]));
+        assert_eq!(img.get_pixel(390, 290), &Rgba([0, 0, 0, 255]));
+
+        // A logo larger than an image shrinks into the margins.
+        let stamp = settings(false)
+            .stamp(logo.clone(), &query("wm=true&wm_scale=100&wm_pos=top-left"))
+            .unwrap();
+        let fitted = stamp.fit(60, 60);
+        assert_eq!(fitted.dimensions(), (40, 20));
+        assert_eq!(stamp.origin((60, 60), fitted.dimensions()), (10, 10));
+
+        // An enforced watermark ignores a query.
+        let stamp = settings(true)
+            .stamp(
+                logo.clone(),
+                &query("wm_opacity=0&wm_scale=1&wm_pos=center&wm_margin=100000"),
+            )
+            .unwrap();
+        let fitted = stamp.fit(400, 300);
+        assert_eq!(fitted.dimensions(), (80, 40));
+        assert_eq!(fitted.get_pixel(0, 0)[3], 100);
+        assert_eq!(stamp.origin((400, 300), fitted.dimensions()), (310, 250));
+
+        // A margin wider than an image does not overflow.
+        let stamp = settings(false)
+            .stamp(logo, &query("wm=true&wm_margin=4294967295"))
+            .unwrap();
+        assert_eq!(stamp.fit(400, 300).dimensions(), (1, 1));
+    }
+}